@@ -0,0 +1,211 @@
+use std::sync::{LazyLock, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System, get_current_pid};
+
+static COLLECTIONS: AtomicU64 = AtomicU64::new(0);
+static COLLECTION_TOTAL: AtomicU64 = AtomicU64::new(0);
+static COLLECTION_MIN: AtomicU64 = AtomicU64::new(u64::MAX);
+static COLLECTION_MAX: AtomicU64 = AtomicU64::new(0);
+static PARSED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static SKIPPED: AtomicU64 = AtomicU64::new(0);
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static PUBLISH_MAX: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+static SPOOL: AtomicI64 = AtomicI64::new(0);
+static SYSTEM: LazyLock<Mutex<System>> = LazyLock::new(|| Mutex::new(System::new()));
+static STATUS: LazyLock<Mutex<Status>> = LazyLock::new(|| Mutex::new(Status{started: Utc::now(), ..Default::default()}));
+
+/// The progress of the collection loop and the outcome of the last publish.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status
+{
+  pub started: DateTime<Utc>,
+  pub last_collection: Option<DateTime<Utc>>,
+  pub last_publish: Option<DateTime<Utc>>,
+  /// Whether every output accepted the last batch.
+  pub last_publish_succeeded: Option<bool>,
+  /// The number of containers in the last `docker stats` output.
+  pub containers: usize,
+  /// The time of the interval currently being gathered.
+  pub interval: Option<DateTime<Utc>>,
+  pub spool_depth: i64,
+  /// The number of batches waiting in the longest output queue.
+  pub queued: usize,
+  /// The number of batches spooled to disk.
+  pub spooled: usize,
+  /// The number of batches dropped by an output because its queue was full, since the agent started.
+  pub dropped: u64
+}
+
+/// The agent's own statistics since the previous row.  Durations are in milliseconds.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AgentStats
+{
+  /// Number of times `docker stats` was run.
+  pub collections: u64,
+  pub collection_min: f64,
+  pub collection_avg: f64,
+  pub collection_max: f64,
+  /// Number of `docker stats` records parsed.
+  pub parsed: u64,
+  /// Number of `docker stats` records that could not be parsed.
+  pub rejected: u64,
+  /// Number of samples skipped because collection overran the sample period.
+  pub skipped: u64,
+  /// Number of times `docker stats` was killed for not exiting within the timeout.
+  pub timeouts: u64,
+  /// The longest time taken by an output to publish a batch.
+  pub publish: f64,
+  /// Bytes sent to network outputs.
+  pub bytes: u64,
+  /// Number of times publishing to an output was retried.
+  pub retries: u64,
+  /// Number of times an output gave up on a batch.
+  pub failures: u64,
+  /// Number of batches waiting to be, or being, published.
+  pub spool: i64,
+  /// Resident set size of the agent in bytes.
+  pub rss: u64,
+  /// CPU usage of the agent since the previous row, as a percentage of a single CPU.
+  pub cpu: f64
+}
+
+fn millis(micros: u64) -> f64
+{
+  micros as f64 / 1000.0
+}
+
+/// Record a `docker stats` invocation.
+pub fn collected(duration: Duration, parsed: usize, rejected: usize)
+{
+  let micros = duration.as_micros() as u64;
+  COLLECTIONS.fetch_add(1, Ordering::Relaxed);
+  COLLECTION_TOTAL.fetch_add(micros, Ordering::Relaxed);
+  COLLECTION_MIN.fetch_min(micros, Ordering::Relaxed);
+  COLLECTION_MAX.fetch_max(micros, Ordering::Relaxed);
+  PARSED.fetch_add(parsed as u64, Ordering::Relaxed);
+  REJECTED.fetch_add(rejected as u64, Ordering::Relaxed);
+
+  let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+  status.last_collection = Some(Utc::now());
+  status.containers = parsed;
+}
+
+/// Record samples skipped because collection overran the sample period.
+pub fn skipped(samples: u32)
+{
+  SKIPPED.fetch_add(samples as u64, Ordering::Relaxed);
+}
+
+/// Record `docker stats` being killed for not exiting within the timeout.
+pub fn timed_out()
+{
+  TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a batch having been handled by all the outputs, with the longest time an output took
+/// to publish it and whether they all accepted it.
+pub fn published(duration: Duration, succeeded: bool)
+{
+  PUBLISH_MAX.fetch_max(duration.as_micros() as u64, Ordering::Relaxed);
+
+  let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+  status.last_publish = Some(Utc::now());
+  status.last_publish_succeeded = Some(succeeded);
+}
+
+/// Record the time of the interval being gathered.
+pub fn interval(time: DateTime<Utc>)
+{
+  STATUS.lock().unwrap_or_else(|e| e.into_inner()).interval = Some(time);
+}
+
+pub fn status() -> Status
+{
+  let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+  status.spool_depth = SPOOL.load(Ordering::Relaxed);
+  status
+}
+
+/// Record bytes sent to a network output.
+pub fn sent(bytes: usize)
+{
+  BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn retried()
+{
+  RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn failed()
+{
+  FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a batch being handed to the outputs.
+pub fn enqueued()
+{
+  SPOOL.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a batch having been delivered to, or dropped by, all the outputs.
+pub fn dequeued()
+{
+  SPOOL.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Record the longest output queue's length and the number of spooled batches.
+pub fn queue(queued: usize, spooled: usize)
+{
+  let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+  status.queued = queued;
+  status.spooled = spooled;
+}
+
+/// Record a batch dropped by an output because its queue was full.
+pub fn dropped()
+{
+  STATUS.lock().unwrap_or_else(|e| e.into_inner()).dropped += 1;
+}
+
+fn process() -> (u64, f64)
+{
+  let Ok(pid) = get_current_pid() else { return (0, 0.0); };
+  let mut system = SYSTEM.lock().unwrap_or_else(|e| e.into_inner());
+  system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, ProcessRefreshKind::nothing().with_memory().with_cpu());
+  system.process(pid).map(|p| (p.memory(), p.cpu_usage() as f64)).unwrap_or_default()
+}
+
+/// The statistics since the previous call, resetting the counters.
+pub fn take() -> AgentStats
+{
+  let collections = COLLECTIONS.swap(0, Ordering::Relaxed);
+  let total = COLLECTION_TOTAL.swap(0, Ordering::Relaxed);
+  let min = COLLECTION_MIN.swap(u64::MAX, Ordering::Relaxed);
+  let (rss, cpu) = process();
+
+  AgentStats{
+    collections,
+    collection_min: if collections == 0 { 0.0 } else { millis(min) },
+    collection_avg: millis(total.checked_div(collections).unwrap_or_default()),
+    collection_max: millis(COLLECTION_MAX.swap(0, Ordering::Relaxed)),
+    parsed: PARSED.swap(0, Ordering::Relaxed),
+    rejected: REJECTED.swap(0, Ordering::Relaxed),
+    skipped: SKIPPED.swap(0, Ordering::Relaxed),
+    timeouts: TIMEOUTS.swap(0, Ordering::Relaxed),
+    publish: millis(PUBLISH_MAX.swap(0, Ordering::Relaxed)),
+    bytes: BYTES.swap(0, Ordering::Relaxed),
+    retries: RETRIES.swap(0, Ordering::Relaxed),
+    failures: FAILURES.swap(0, Ordering::Relaxed),
+    spool: SPOOL.load(Ordering::Relaxed),
+    rss,
+    cpu
+  }
+}
@@ -0,0 +1,302 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use parquet::{
+  basic::Compression,
+  data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+  file::{properties::WriterProperties, writer::SerializedFileWriter},
+  schema::parser::parse_message_type
+};
+
+use super::{ArchiveFormat, Cli};
+use super::sink::{Batch, Sink};
+use super::stats::{DiskStats, Stats};
+
+enum Values
+{
+  Str(Vec<String>),
+  F64(Vec<f64>),
+  /// A column that QuestDB leaves null for some rows.
+  OptionalF64(Vec<Option<f64>>),
+  I64(Vec<i64>),
+  Timestamp(Vec<DateTime<Utc>>)
+}
+
+/// Columnar representation of the rows written for a table, with the same column names and
+/// types as the QuestDB table.
+struct Table
+{
+  name: String,
+  columns: Vec<(&'static str, Values)>
+}
+
+impl Table
+{
+  fn rows(&self) -> usize
+  {
+    match self.columns.first()
+    {
+      Some((_, Values::Str(v))) => v.len(),
+      Some((_, Values::F64(v))) => v.len(),
+      Some((_, Values::OptionalF64(v))) => v.len(),
+      Some((_, Values::I64(v))) => v.len(),
+      Some((_, Values::Timestamp(v))) => v.len(),
+      None => 0
+    }
+  }
+
+  fn schema(&self) -> String
+  {
+    let fields : Vec<String> = self.columns.iter().map(|(name, values)| match values
+    {
+      Values::Str(_) => format!("REQUIRED BYTE_ARRAY {} (STRING);", name),
+      Values::F64(_) => format!("REQUIRED DOUBLE {};", name),
+      Values::OptionalF64(_) => format!("OPTIONAL DOUBLE {};", name),
+      Values::I64(_) => format!("REQUIRED INT64 {};", name),
+      Values::Timestamp(_) => format!("REQUIRED INT64 {} (TIMESTAMP(MICROS,true));", name)
+    }).collect();
+    format!("message schema {{ {} }}", fields.join(" "))
+  }
+
+  fn write_parquet(&self, path: &Path) -> Result<(), String>
+  {
+    let schema = Arc::new(parse_message_type(&self.schema()).map_err(|e| e.to_string())?);
+    let props = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    let file = File::create(path).map_err(|e| format!("Failed to create {}. {}", path.display(), e))?;
+    let mut writer = SerializedFileWriter::new(file, schema, props).map_err(|e| e.to_string())?;
+
+    let mut group = writer.next_row_group().map_err(|e| e.to_string())?;
+    for (_, values) in &self.columns
+    {
+      let Some(mut column) = group.next_column().map_err(|e| e.to_string())? else { break; };
+      let result = match values
+      {
+        Values::Str(v) =>
+        {
+          let v : Vec<ByteArray> = v.iter().map(|s| ByteArray::from(s.as_str())).collect();
+          column.typed::<ByteArrayType>().write_batch(&v, None, None)
+        },
+        Values::F64(v) => column.typed::<DoubleType>().write_batch(v, None, None),
+        Values::OptionalF64(v) =>
+        {
+          let levels : Vec<i16> = v.iter().map(|v| v.is_some() as i16).collect();
+          let v : Vec<f64> = v.iter().flatten().copied().collect();
+          column.typed::<DoubleType>().write_batch(&v, Some(&levels), None)
+        },
+        Values::I64(v) => column.typed::<Int64Type>().write_batch(v, None, None),
+        Values::Timestamp(v) =>
+        {
+          let v : Vec<i64> = v.iter().map(|t| t.timestamp_micros()).collect();
+          column.typed::<Int64Type>().write_batch(&v, None, None)
+        }
+      };
+      result.map_err(|e| e.to_string())?;
+      column.close().map_err(|e| e.to_string())?;
+    }
+    group.close().map_err(|e| e.to_string())?;
+    writer.close().map_err(|e| e.to_string())?;
+    Ok(())
+  }
+
+  fn write_csv(&self, path: &Path) -> Result<(), String>
+  {
+    let exists = fs::metadata(path).map(|m| m.len() > 0).unwrap_or(false);
+    let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("Failed to open {}. {}", path.display(), e))?;
+    let mut writer = csv::Writer::from_writer(file);
+
+    if !exists { writer.write_record(self.columns.iter().map(|(name, _)| *name)).map_err(|e| e.to_string())?; }
+    for row in 0..self.rows()
+    {
+      let record : Vec<String> = self.columns.iter().map(|(_, values)| match values
+      {
+        Values::Str(v) => v[row].clone(),
+        Values::F64(v) => v[row].to_string(),
+        Values::OptionalF64(v) => v[row].map(|v| v.to_string()).unwrap_or_default(),
+        Values::I64(v) => v[row].to_string(),
+        Values::Timestamp(v) => v[row].to_rfc3339_opts(SecondsFormat::Micros, true)
+      }).collect();
+      writer.write_record(&record).map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    Ok(())
+  }
+}
+
+fn containers(cli: &Cli, batch: &Batch) -> Table
+{
+  let stats = &batch.stats;
+  let strings = |f: fn(&Stats) -> &str| Values::Str(stats.iter().map(|s| f(s).to_string()).collect());
+  let floats = |f: fn(&Stats) -> f64| Values::F64(stats.iter().map(f).collect());
+
+  Table{name: cli.table.clone(), columns: vec![
+    ("host", Values::Str(vec![cli.host.clone(); stats.len()])),
+    ("container", strings(|s| &s.container)),
+    ("name", strings(|s| &s.name)),
+    ("id", strings(|s| &s.id)),
+    ("cpu", floats(|s| s.cpuPercentage)),
+    ("memory_percentage", floats(|s| s.memoryPercentage)),
+    ("pids", Values::I64(stats.iter().map(|s| s.pids as i64).collect())),
+    ("block_io_in", floats(|s| s.blockIO.incoming.bytes())),
+    ("block_io_out", floats(|s| s.blockIO.outgoing.bytes())),
+    ("net_io_in", floats(|s| s.netIO.incoming.bytes())),
+    ("net_io_out", floats(|s| s.netIO.outgoing.bytes())),
+    ("memory_use", floats(|s| s.memoryUsage.bytes())),
+    ("total_memory", floats(|s| s.totalMemory.bytes())),
+    ("coverage", Values::OptionalF64(vec![batch.partial(); stats.len()])),
+    ("timestamp", Values::Timestamp(stats.iter().map(|s| s.time).collect()))
+  ]}
+}
+
+fn disks(cli: &Cli, batch: &Batch) -> Table
+{
+  let disks = &batch.disks;
+  let strings = |f: fn(&DiskStats) -> &str| Values::Str(disks.iter().map(|d| f(d).to_string()).collect());
+  let ints = |f: fn(&DiskStats) -> u64| Values::I64(disks.iter().map(|d| f(d) as i64).collect());
+
+  Table{name: cli.disk_table.clone(), columns: vec![
+    ("host", Values::Str(vec![cli.host.clone(); disks.len()])),
+    ("name", strings(|d| &d.name)),
+    ("file_system", strings(|d| &d.fileSystem)),
+    ("mount_point", strings(|d| &d.mountPoint)),
+    ("type", strings(|d| &d.kind)),
+    ("available_space", ints(|d| d.availableSpace)),
+    ("percentage_use", Values::F64(disks.iter().map(|d| d.percentage()).collect())),
+    ("read_bytes", ints(|d| d.readBytes)),
+    ("write_bytes", ints(|d| d.writtenBytes)),
+    ("timestamp", Values::Timestamp(vec![batch.time; disks.len()]))
+  ]}
+}
+
+/// Appends each interval's rows to files under `<dir>/<table>/date=<YYYY-MM-DD>/`.  Parquet
+/// files cannot be appended to, so a file is written per interval, numbered when there is
+/// already one for the interval.  CSV rows are appended to a daily file per host.
+pub struct Archive
+{
+  cli: Cli,
+  dir: PathBuf
+}
+
+impl Archive
+{
+  pub fn new(cli: &Cli) -> Archive
+  {
+    Archive{cli: cli.clone(), dir: PathBuf::from(cli.archive_dir.clone().unwrap_or_default())}
+  }
+
+  fn write(&self, table: &Table, time: DateTime<Utc>) -> Result<(), String>
+  {
+    if table.rows() == 0 { return Ok(()); }
+
+    let dir = self.dir.join(&table.name).join(format!("date={}", time.format("%Y-%m-%d")));
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}. {}", dir.display(), e))?;
+
+    let host : String = self.cli.host.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    match self.cli.archive_format
+    {
+      ArchiveFormat::Parquet =>
+      {
+        // The partial interval published on shutdown shares its time with the first interval
+        // after a restart, so neither may overwrite the other.
+        let name = format!("{}-{}", host, time.format("%H%M%S"));
+        let mut path = dir.join(format!("{}.parquet", name));
+        let mut sequence = 1;
+        while path.exists()
+        {
+          path = dir.join(format!("{}-{}.parquet", name, sequence));
+          sequence += 1;
+        }
+        let tmp = path.with_extension("parquet.tmp");
+        table.write_parquet(&tmp)?;
+        fs::rename(&tmp, &path).map_err(|e| format!("Failed to rename {}. {}", tmp.display(), e))?;
+        info!("Archived {} rows to {}", table.rows(), path.display());
+      },
+      ArchiveFormat::Csv =>
+      {
+        let path = dir.join(format!("{}.csv", host));
+        table.write_csv(&path)?;
+        info!("Archived {} rows to {}", table.rows(), path.display());
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Sink for Archive
+{
+  fn name(&self) -> String
+  {
+    format!("Archive {}", self.dir.display())
+  }
+
+  fn publish(&mut self, batch: &Batch) -> Result<(), String>
+  {
+    self.write(&containers(&self.cli, batch), batch.time)?;
+    self.write(&disks(&self.cli, batch), batch.time)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::env;
+  use std::process;
+  use parquet::file::reader::{FileReader, SerializedFileReader};
+  use parquet::record::Field;
+
+  use super::*;
+  use super::super::agent::AgentStats;
+  use super::super::cli;
+
+  fn batch(coverage: f64) -> Batch
+  {
+    let mut stat = Stats::new();
+    stat.name = "web".to_string();
+    stat.time = DateTime::UNIX_EPOCH;
+    Batch{time: DateTime::UNIX_EPOCH, stats: vec![stat], disks: Vec::new(), metrics: Vec::new(), agent: AgentStats::default(), coverage,
+      duration: 0.0}
+  }
+
+  fn archive(format: &str) -> (Archive, PathBuf)
+  {
+    let dir = env::temp_dir().join(format!("container-statsd-{}-archive-{}", process::id(), format));
+    let _ = fs::remove_dir_all(&dir);
+    let cli = cli(&["--node", "edge1", "--archive-dir", &dir.to_string_lossy(), "--archive-format", format]);
+    (Archive::new(&cli), dir.join("containerStats").join("date=1970-01-01"))
+  }
+
+  #[test]
+  fn parquet_files_for_the_same_interval_are_numbered()
+  {
+    let (mut archive, dir) = archive("parquet");
+    archive.publish(&batch(0.25)).unwrap();
+    archive.publish(&batch(0.75)).unwrap();
+
+    let coverage = |name: &str| -> Vec<Field>
+    {
+      let reader = SerializedFileReader::new(File::open(dir.join(name)).unwrap()).unwrap();
+      reader.get_row_iter(None).unwrap().map(|row| row.unwrap().get_column_iter().find(|(n, _)| *n == "coverage").unwrap().1.clone()).collect()
+    };
+    assert_eq!(coverage("edge1-000000.parquet"), vec![Field::Double(0.25)]);
+    assert_eq!(coverage("edge1-000000-1.parquet"), vec![Field::Double(0.75)]);
+
+    archive.publish(&batch(1.0)).unwrap();
+    assert_eq!(coverage("edge1-000000-2.parquet"), vec![Field::Null]);
+  }
+
+  #[test]
+  fn csv_coverage_is_empty_for_full_intervals()
+  {
+    let (mut archive, dir) = archive("csv");
+    archive.publish(&batch(0.5)).unwrap();
+    archive.publish(&batch(1.0)).unwrap();
+
+    let content = fs::read_to_string(dir.join("edge1.csv")).unwrap();
+    let lines : Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(",total_memory,coverage,timestamp"), "{}", lines[0]);
+    assert!(lines[1].ends_with(",0.5,1970-01-01T00:00:00.000000Z"), "{}", lines[1]);
+    assert!(lines[2].ends_with(",0,,1970-01-01T00:00:00.000000Z"), "{}", lines[2]);
+  }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use log::{debug, warn};
+use serde_json::Value;
+
+use super::agent;
+use super::error::{Error, Result};
+use super::filter::Metadata;
+use super::stats::{RawStats, Stats, createStats};
+
+/// The timeout for `docker stats` in the `snapshot` and `top` commands.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Container metadata by the container ID reported by `docker stats`.
+static METADATA: LazyLock<Mutex<HashMap<String, Metadata>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Read the pipe to the end on a separate thread, so that the child does not block on a full pipe.
+fn read<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>>
+{
+  thread::spawn(move ||
+      {
+        let mut buffer : Vec<u8> = Vec::new();
+        if let Some(mut pipe) = pipe && let Err(e) = pipe.read_to_end(&mut buffer) { warn!("Failed to read docker output. {}", e); }
+        buffer
+      })
+}
+
+/// Kill the child and reap it.
+fn kill(child: &mut Child)
+{
+  if let Err(e) = child.kill() { warn!("Failed to kill docker process {}. {}", child.id(), e); }
+  if let Err(e) = child.wait() { warn!("Failed to reap docker process {}. {}", child.id(), e); }
+}
+
+/// Run the command to completion, returning its exit status, stdout and stderr.  The command is
+/// killed if it does not exit within the timeout or the agent is terminated.
+fn output(command: &mut Command, timeout: Duration, term: &Arc<AtomicBool>) -> Result<(ExitStatus, Vec<u8>, Vec<u8>)>
+{
+  let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().
+      map_err(|e| Error::Collect(format!("Failed to run docker. {}", e)))?;
+  let stdout = read(child.stdout.take());
+  let stderr = read(child.stderr.take());
+
+  let started = Instant::now();
+  loop
+  {
+    match child.try_wait()
+    {
+      Ok(Some(status)) => return Ok((status, stdout.join().unwrap_or_default(), stderr.join().unwrap_or_default())),
+      Ok(None) => {},
+      Err(e) =>
+      {
+        kill(&mut child);
+        return Err(Error::Collect(format!("Failed to wait for docker. {}", e)));
+      }
+    }
+
+    if term.load(Ordering::Relaxed)
+    {
+      kill(&mut child);
+      return Err(Error::Collect("Interrupted while running docker stats.".to_string()));
+    }
+    if started.elapsed() >= timeout
+    {
+      kill(&mut child);
+      agent::timed_out();
+      return Err(Error::Collect(format!("docker stats did not exit within {}s and was killed.", timeout.as_secs())));
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+}
+
+/// Run `docker stats` once.  Records that cannot be parsed are logged and counted as rejected.
+/// All the records are timestamped with the time the sample was taken.
+pub fn statistics(timeout: Duration, term: &Arc<AtomicBool>) -> Result<Vec<Stats>>
+{
+  let started = Instant::now();
+  let time = Utc::now();
+  let (status, stdout, stderr) = output(Command::new("docker").arg("stats").arg("--no-stream").arg("--format=json"), timeout, term)?;
+  if !status.success()
+  {
+    return Err(Error::Collect(format!("docker stats exited with {}. {}", status, String::from_utf8_lossy(&stderr).trim())));
+  }
+
+  let mut reader = BufReader::new(stdout.as_slice());
+  let mut vec : Vec<Stats> = Vec::with_capacity(32);
+  let mut rejected = 0;
+  let mut line = String::new();
+  while reader.read_line(&mut line).map_err(|e| Error::Collect(format!("Failed to read docker stats output. {}", e)))? > 0
+  {
+    match serde_json::from_str::<RawStats>(line.trim()).map_err(|e| Error::Parse(e.to_string())).and_then(|raw| createStats(&raw))
+    {
+      Ok(mut stats) =>
+      {
+        stats.time = time;
+        vec.push(stats);
+      },
+      Err(e) =>
+      {
+        warn!("Rejected docker stats record {}. {}", line.trim(), e);
+        rejected += 1;
+      }
+    }
+    line.clear();
+  }
+
+  agent::collected(started.elapsed(), vec.len(), rejected);
+  Ok(vec)
+}
+
+fn parse_metadata(value: &Value) -> Metadata
+{
+  let labels = value["Config"]["Labels"].as_object().map(|labels| labels.iter().
+      filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string()))).collect()).unwrap_or_default();
+  Metadata{image: value["Config"]["Image"].as_str().unwrap_or_default().to_string(), labels}
+}
+
+/// The image and labels of the containers.  Containers not seen before are inspected, and those
+/// no longer in the statistics are forgotten.
+pub fn metadata(stats: &[Stats], timeout: Duration, term: &Arc<AtomicBool>) -> Result<HashMap<String, Metadata>>
+{
+  let mut cache = METADATA.lock().unwrap_or_else(|e| e.into_inner());
+  cache.retain(|id, _| stats.iter().any(|s| s.id == *id));
+  let mut missing : Vec<&str> = stats.iter().map(|s| s.id.as_str()).filter(|id| !cache.contains_key(*id)).collect();
+  missing.sort();
+  missing.dedup();
+  if missing.is_empty() { return Ok(cache.clone()); }
+
+  debug!("Inspecting {} containers", missing.len());
+  // Exits with an error when a container has gone since `docker stats`, but still prints the others.
+  let (status, stdout, stderr) = output(Command::new("docker").arg("inspect").args(&missing), timeout, term)?;
+  let values : Vec<Value> = serde_json::from_slice(&stdout).map_err(|e| Error::Collect(
+      format!("Failed to parse docker inspect output, exited with {}. {} {}", status, e, String::from_utf8_lossy(&stderr).trim())))?;
+  for id in missing
+  {
+    match values.iter().find(|v| v["Id"].as_str().is_some_and(|i| i.starts_with(id)))
+    {
+      Some(value) => { cache.insert(id.to_string(), parse_metadata(value)); },
+      None => warn!("Container {} was not found by docker inspect. {}", id, String::from_utf8_lossy(&stderr).trim())
+    }
+  }
+  Ok(cache.clone())
+}
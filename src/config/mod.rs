@@ -0,0 +1,225 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use clap::{ArgAction, Args, Command};
+use clap::parser::ValueSource;
+use serde_json::Value;
+
+use super::{Cli, Commands, delimited, environment, parse, variable};
+
+/// The `run` arguments, without required arguments so that they may be specified in the
+/// configuration file instead.
+fn command() -> Command
+{
+  environment(Cli::augment_args(Command::new("run"))).mut_args(|a| a.required(false)).ignore_errors(true)
+}
+
+fn load(path: &str) -> Result<Value, String>
+{
+  let content = fs::read_to_string(path).map_err(|e| format!("Failed to read configuration file {}. {}", path, e))?;
+  match Path::new(path).extension().and_then(|e| e.to_str())
+  {
+    Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| format!("Failed to parse configuration file {}. {}", path, e)),
+    _ => toml::from_str(&content).map_err(|e| format!("Failed to parse configuration file {}. {}", path, e))
+  }
+}
+
+fn scalar(value: &Value) -> Option<String>
+{
+  match value
+  {
+    Value::String(s) => Some(s.clone()),
+    Value::Number(n) => Some(n.to_string()),
+    Value::Bool(b) => Some(b.to_string()),
+    _ => None
+  }
+}
+
+/// Insert arguments for the options set in the environment or the configuration file, and not
+/// on the command line, immediately after the `run` subcommand.  Environment variables are
+/// looked up with `env`, and take precedence over the file.  Options in the file are named by
+/// their long argument, with dashes or underscores (`stats-table`, `stats_table`), or field
+/// name (`table`).
+pub fn arguments(mut args: Vec<OsString>, env: impl Fn(&str) -> Option<String>) -> Result<Vec<OsString>, String>
+{
+  if args.get(1).map(|a| a != "run").unwrap_or(true) { return Ok(args); }
+
+  let command = command();
+  let Ok(matches) = command.clone().try_get_matches_from(&args[1..]) else { return Ok(args); };
+  let specified = |id: &str| matches!(matches.value_source(id), Some(ValueSource::CommandLine));
+
+  let mut vec : Vec<OsString> = Vec::new();
+  let mut environment : Vec<&str> = Vec::new();
+  for arg in command.get_arguments()
+  {
+    let Some(long) = arg.get_long() else { continue; };
+    let name = variable(long);
+    if specified(arg.get_id().as_str()) { continue; }
+    let Some(value) = env(&name) else { continue; };
+
+    match arg.get_action()
+    {
+      ArgAction::SetTrue => match value.to_lowercase().as_str()
+      {
+        "true" | "1" | "yes" | "on" => vec.push(format!("--{}", long).into()),
+        "false" | "0" | "no" | "off" | "" => {},
+        _ => return Err(format!("Invalid value {} for {}, expected true or false", value, name))
+      },
+      _ if delimited(arg) =>
+      {
+        for v in value.split(',').filter(|v| !v.is_empty()) { vec.push(format!("--{}={}", long, v).into()); }
+      },
+      _ => vec.push(format!("--{}={}", long, value).into())
+    }
+    environment.push(arg.get_id().as_str());
+  }
+
+  let path = matches.get_one::<String>("config").cloned().or_else(|| env(&variable("config")));
+  if let Some(path) = path
+  {
+    let Value::Object(options) = load(&path)? else { return Err(format!("Configuration file {} is not a table of options", path)); };
+    for (key, value) in &options
+    {
+      let (id, long) = (key.replace('-', "_"), key.replace('_', "-"));
+      let arg = command.get_arguments().find(|a| a.get_id() == id.as_str() || a.get_long() == Some(long.as_str())).
+          ok_or_else(|| format!("Unknown option {} in configuration file {}", key, path))?;
+      let id = arg.get_id().as_str();
+      if id == "config" || specified(id) || environment.contains(&id) { continue; }
+
+      let long = arg.get_long().ok_or_else(|| format!("Option {} cannot be set in configuration file {}", key, path))?;
+      let invalid = || format!("Invalid value for {} in configuration file {}", key, path);
+      match (arg.get_action(), value)
+      {
+        (ArgAction::SetTrue, Value::Bool(true)) => vec.push(format!("--{}", long).into()),
+        (ArgAction::SetTrue, Value::Bool(false)) => {},
+        (_, Value::Array(values)) =>
+        {
+          for v in values { vec.push(format!("--{}={}", long, scalar(v).ok_or_else(invalid)?).into()); }
+        },
+        (_, v) => vec.push(format!("--{}={}", long, scalar(v).ok_or_else(invalid)?).into())
+      }
+    }
+  }
+
+  args.splice(2..2, vec);
+  Ok(args)
+}
+
+/// Re-read the command line arguments and configuration file.
+pub fn reload() -> Result<Cli, String>
+{
+  let args = arguments(super::arguments(), |name| std::env::var(name).ok())?;
+  match parse(args).map_err(|e| e.to_string())?.command
+  {
+    Commands::Run(cli) => Ok(*cli),
+    _ => Err("Configuration can only be reloaded when running as a daemon".to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::collections::HashMap;
+  use std::env;
+  use std::process;
+
+  use super::*;
+
+  /// Write the configuration to a temporary file, returning its path.
+  fn file(name: &str, content: &str) -> String
+  {
+    let path = env::temp_dir().join(format!("container-statsd-{}-{}", process::id(), name));
+    fs::write(&path, content).unwrap();
+    path.to_string_lossy().to_string()
+  }
+
+  /// Resolve the arguments with the environment variables, without touching the process environment.
+  fn resolve(args: &[&str], variables: &[(&str, &str)]) -> Result<Cli, String>
+  {
+    let args : Vec<OsString> = ["container-statsd", "run"].iter().chain(args).map(OsString::from).collect();
+    let variables : HashMap<String, String> = variables.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    match parse(arguments(args, |name| variables.get(name).cloned())?).map_err(|e| e.to_string())?.command
+    {
+      Commands::Run(cli) => Ok(*cli),
+      _ => unreachable!()
+    }
+  }
+
+  fn run(args: &[&str]) -> Result<Cli, String>
+  {
+    resolve(args, &[])
+  }
+
+  #[test]
+  fn command_line_then_environment_then_file()
+  {
+    let path = file("precedence.toml", r#"
+graphite-prefix = "file"
+influx_database = "file"
+influx-bucket = "file"
+table = "fileStats"
+"#);
+    let variables = [("CONTAINER_STATSD_GRAPHITE_PREFIX", "environment"), ("CONTAINER_STATSD_INFLUX_DATABASE", "environment")];
+
+    let cli = resolve(&["--config", &path, "--graphite-prefix", "command"], &variables).unwrap();
+    assert_eq!(cli.graphite_prefix, "command");
+    assert_eq!(cli.influx_database, "environment");
+    assert_eq!(cli.influx_bucket, "file");
+    assert_eq!(cli.table, "fileStats");
+    assert_eq!(cli.graphite_template, "{prefix}.{host}.{name}.{metric}");
+  }
+
+  #[test]
+  fn file_values_may_be_arrays_numbers_and_flags()
+  {
+    let path = file("types.yaml", "block-device: [sda, sdb]\njson_retain: 3\ngraphite-tagged: true\ndry-run: false\n");
+    let cli = run(&["--config", &path]).unwrap();
+    assert_eq!(cli.disks, vec!["sda", "sdb"]);
+    assert_eq!(cli.json_retain, 3);
+    assert!(cli.graphite_tagged);
+    assert!(!cli.dry_run);
+  }
+
+  #[test]
+  fn repeated_command_line_options_replace_the_file()
+  {
+    let path = file("repeated.toml", "block-device = [\"sda\", \"sdb\"]\n");
+    assert_eq!(run(&["--config", &path, "-b", "nvme0n1"]).unwrap().disks, vec!["nvme0n1"]);
+  }
+
+  #[test]
+  fn file_filters_may_contain_commas()
+  {
+    let path = file("filters.toml", "include = [\"re:^web-[0-9]{1,3}$\", \"db\"]\n");
+    let cli = run(&["--config", &path]).unwrap();
+    assert_eq!(cli.include.len(), 2);
+    assert_eq!(cli.include[1].field, super::super::filter::Field::Name);
+  }
+
+  #[test]
+  fn environment_lists_flags_and_the_configuration_file()
+  {
+    let path = file("environment.toml", "block-device = [\"sda\"]\ngraphite-tagged = true\n");
+    let variables = [("CONTAINER_STATSD_CONFIG", path.as_str()), ("CONTAINER_STATSD_BLOCK_DEVICE", "nvme0n1,nvme1n1"),
+      ("CONTAINER_STATSD_INCLUDE", "re:^a{1,3}$"), ("CONTAINER_STATSD_DRY_RUN", "true"), ("CONTAINER_STATSD_GRAPHITE_TAGGED", "false")];
+    let cli = resolve(&[], &variables).unwrap();
+    assert_eq!(cli.disks, vec!["nvme0n1", "nvme1n1"]);
+    assert_eq!(cli.include.len(), 1);
+    assert!(cli.dry_run);
+    assert!(!cli.graphite_tagged);
+
+    // Commas only separate values in the environment.
+    assert_eq!(run(&["-b", "a,b"]).unwrap().disks, vec!["a,b"]);
+    assert!(resolve(&[], &[("CONTAINER_STATSD_DRY_RUN", "maybe")]).unwrap_err().contains("CONTAINER_STATSD_DRY_RUN"));
+  }
+
+  #[test]
+  fn invalid_files_are_rejected()
+  {
+    let unknown = file("unknown.toml", "colour = \"blue\"\n");
+    assert!(run(&["--config", &unknown]).unwrap_err().contains("Unknown option colour"));
+    let invalid = file("invalid.toml", "block-device = [[\"sda\"]]\n");
+    assert!(run(&["--config", &invalid]).unwrap_err().contains("Invalid value for block-device"));
+    assert!(run(&["--config", "/nonexistent/container-statsd.toml"]).unwrap_err().contains("Failed to read"));
+  }
+}
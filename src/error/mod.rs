@@ -0,0 +1,53 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+/// Errors raised by the agent.  Only configuration errors are fatal to the daemon, the others
+/// are logged, reported to systemd and retried on the next sample or interval.
+#[derive(Debug)]
+pub enum Error
+{
+  /// Invalid options, or an environment that does not match them.
+  Config(String),
+  /// Running `docker stats` or reading its output failed.
+  Collect(String),
+  /// A `docker stats` value could not be parsed.
+  Parse(String),
+  /// Publishing to an output failed.
+  Publish(String),
+  Io(io::Error)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error
+{
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+  {
+    match self
+    {
+      Error::Config(message) => write!(f, "Invalid configuration. {}", message),
+      Error::Collect(message) => write!(f, "Failed to collect container statistics. {}", message),
+      Error::Parse(message) => write!(f, "Failed to parse container statistics. {}", message),
+      Error::Publish(message) => write!(f, "Failed to publish. {}", message),
+      Error::Io(e) => write!(f, "{}", e)
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error
+{
+  fn from(e: io::Error) -> Error
+  {
+    Error::Io(e)
+  }
+}
+
+impl From<questdb::Error> for Error
+{
+  fn from(e: questdb::Error) -> Error
+  {
+    Error::Publish(e.to_string())
+  }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use glob::Pattern;
+use log::debug;
+use regex::Regex;
+
+use super::stats::Stats;
+
+/// The container attribute a filter matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Field
+{
+  Name,
+  Image,
+  /// The value of the label with the key.
+  Label(String)
+}
+
+#[derive(Clone, Debug)]
+enum Matcher
+{
+  Glob(Pattern),
+  Regex(Regex)
+}
+
+impl Matcher
+{
+  fn matches(&self, value: &str) -> bool
+  {
+    match self
+    {
+      Matcher::Glob(pattern) => pattern.matches(value),
+      Matcher::Regex(regex) => regex.is_match(value)
+    }
+  }
+}
+
+/// An `--include` or `--exclude` filter.
+#[derive(Clone, Debug)]
+pub struct Filter
+{
+  pub field: Field,
+  matcher: Matcher
+}
+
+/// The image and labels of a container, from `docker inspect`.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata
+{
+  pub image: String,
+  pub labels: HashMap<String, String>
+}
+
+/// Parse a filter, as `name=<pattern>`, `image=<pattern>` or `label:<key>=<pattern>`.  A
+/// pattern without a field matches the name.  Patterns are globs, or regular expressions when
+/// prefixed with `re:`.
+pub fn filter(value: &str) -> Result<Filter, String>
+{
+  let (field, pattern) = match value.split_once('=')
+  {
+    Some(("name", pattern)) => (Field::Name, pattern),
+    Some(("image", pattern)) => (Field::Image, pattern),
+    Some((field, pattern)) if field.starts_with("label:") =>
+    {
+      let key = &field["label:".len()..];
+      if key.is_empty() { return Err(format!("Invalid filter {}, expected label:<key>=<pattern>", value)); }
+      (Field::Label(key.to_string()), pattern)
+    },
+    _ => (Field::Name, value)
+  };
+
+  let matcher = match pattern.strip_prefix("re:")
+  {
+    Some(regex) => Matcher::Regex(Regex::new(regex).map_err(|e| format!("Invalid regular expression in filter {}. {}", value, e))?),
+    None => Matcher::Glob(Pattern::new(pattern).map_err(|e| format!("Invalid glob in filter {}. {}", value, e))?)
+  };
+  Ok(Filter{field, matcher})
+}
+
+impl Filter
+{
+  /// Whether the container matches.  A label filter does not match containers without the label.
+  fn matches(&self, stat: &Stats, metadata: Option<&Metadata>) -> bool
+  {
+    match &self.field
+    {
+      Field::Name => self.matcher.matches(&stat.name),
+      Field::Image => metadata.is_some_and(|m| self.matcher.matches(&m.image)),
+      Field::Label(key) => metadata.and_then(|m| m.labels.get(key)).is_some_and(|v| self.matcher.matches(v))
+    }
+  }
+}
+
+/// Whether any of the filters needs the container metadata from `docker inspect`.
+pub fn inspect(filters: &[&[Filter]]) -> bool
+{
+  filters.iter().flat_map(|f| f.iter()).any(|f| f.field != Field::Name)
+}
+
+/// Keep the containers that match any of the includes, or all containers when there are none,
+/// and that match none of the excludes.
+pub fn apply(includes: &[Filter], excludes: &[Filter], stats: Vec<Stats>, metadata: &HashMap<String, Metadata>) -> Vec<Stats>
+{
+  if includes.is_empty() && excludes.is_empty() { return stats; }
+
+  let count = stats.len();
+  let vec : Vec<Stats> = stats.into_iter().filter(|stat|
+      {
+        let metadata = metadata.get(&stat.id);
+        (includes.is_empty() || includes.iter().any(|f| f.matches(stat, metadata))) && !excludes.iter().any(|f| f.matches(stat, metadata))
+      }).collect();
+  debug!("Filtered {} of {} containers", count - vec.len(), count);
+  vec
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn stat(id: &str, name: &str) -> Stats
+  {
+    let mut stat = Stats::new();
+    stat.id = id.to_string();
+    stat.name = name.to_string();
+    stat
+  }
+
+  fn metadata(image: &str, labels: &[(&str, &str)]) -> Metadata
+  {
+    Metadata{image: image.to_string(), labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()}
+  }
+
+  #[test]
+  fn filter_parses_fields_and_patterns()
+  {
+    assert_eq!(filter("name=web-*").unwrap().field, Field::Name);
+    assert_eq!(filter("image=ci/*").unwrap().field, Field::Image);
+    assert_eq!(filter("label:com.docker.compose.service=build").unwrap().field, Field::Label("com.docker.compose.service".to_string()));
+
+    let bare = filter("web-?").unwrap();
+    assert_eq!(bare.field, Field::Name);
+    assert!(bare.matches(&stat("1", "web-1"), None));
+    assert!(!bare.matches(&stat("1", "web-10"), None));
+
+    let regex = filter("re:^web-[0-9]{1,3}$").unwrap();
+    assert!(regex.matches(&stat("1", "web-100"), None));
+    assert!(!regex.matches(&stat("1", "web-1000"), None));
+  }
+
+  #[test]
+  fn filter_rejects_invalid_patterns()
+  {
+    assert!(filter("label:=x").unwrap_err().contains("expected label:<key>=<pattern>"));
+    assert!(filter("re:(").unwrap_err().contains("Invalid regular expression"));
+    assert!(filter("name=[").unwrap_err().contains("Invalid glob"));
+  }
+
+  #[test]
+  fn apply_keeps_includes_and_drops_excludes()
+  {
+    let stats = vec![stat("1", "web"), stat("2", "db"), stat("3", "build"), stat("4", "cache")];
+    let metadata = HashMap::from([
+      ("1".to_string(), metadata("nginx:1", &[("tier", "front")])),
+      ("2".to_string(), metadata("postgres:16", &[("tier", "back")])),
+      ("3".to_string(), metadata("ci/runner", &[("tier", "back")]))]);
+    let names = |stats: Vec<Stats>| stats.into_iter().map(|s| s.name).collect::<Vec<String>>();
+
+    assert_eq!(names(apply(&[], &[], stats.clone(), &metadata)), vec!["web", "db", "build", "cache"]);
+    let includes = [filter("label:tier=back").unwrap(), filter("web").unwrap()];
+    assert_eq!(names(apply(&includes, &[], stats.clone(), &metadata)), vec!["web", "db", "build"]);
+    let excludes = [filter("image=ci/*").unwrap()];
+    assert_eq!(names(apply(&includes, &excludes, stats.clone(), &metadata)), vec!["web", "db"]);
+    // Containers without metadata match neither image nor label filters.
+    assert_eq!(names(apply(&[], &[filter("label:tier=re:.*").unwrap()], stats, &metadata)), vec!["cache"]);
+  }
+
+  #[test]
+  fn inspect_only_for_image_and_label_filters()
+  {
+    let names = [filter("web").unwrap(), filter("name=re:^db").unwrap()];
+    assert!(!inspect(&[&names, &[]]));
+    assert!(inspect(&[&names, &[filter("image=ci/*").unwrap()]]));
+    assert!(inspect(&[&[filter("label:a=b").unwrap()]]));
+  }
+
+  #[test]
+  fn filters_are_not_split_on_commas()
+  {
+    let cli = super::super::cli(&["--include", "re:^a{1,3}$", "--exclude", "label:x=a,b"]);
+    assert_eq!(cli.include.len(), 1);
+    assert!(cli.include[0].matches(&stat("1", "aaa"), None));
+    assert_eq!(cli.exclude.len(), 1);
+    assert!(cli.exclude[0].matches(&stat("1", "c"), Some(&metadata("", &[("x", "a,b")]))));
+  }
+}
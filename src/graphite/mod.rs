@@ -0,0 +1,181 @@
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use log::info;
+
+use super::Cli;
+use super::agent;
+use super::sink::{Batch, Sink};
+use super::stats::Stats;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replace characters that would split or break a Graphite path component or tag.
+fn sanitise(value: &str) -> String
+{
+  let value : String = value.chars().map(|c| match c { '.' | ' ' | '\t' | '\n' | ';' | '~' | '=' | '/' | '\\' => '_', _ => c }).collect();
+  if value.is_empty() { "_".to_string() } else { value }
+}
+
+/// Metric names and values for a container, using the QuestDB column names.
+fn values(stat: &Stats) -> [(&'static str, f64); 9]
+{
+  [
+    ("cpu", stat.cpuPercentage),
+    ("memory_percentage", stat.memoryPercentage),
+    ("memory_use", stat.memoryUsage.bytes()),
+    ("total_memory", stat.totalMemory.bytes()),
+    ("pids", stat.pids as f64),
+    ("block_io_in", stat.blockIO.incoming.bytes()),
+    ("block_io_out", stat.blockIO.outgoing.bytes()),
+    ("net_io_in", stat.netIO.incoming.bytes()),
+    ("net_io_out", stat.netIO.outgoing.bytes())
+  ]
+}
+
+/// Format the statistics in the Graphite plaintext protocol.  Paths are built from the
+/// template, or as tagged series when enabled.  Values that are `NaN` or infinite are left out.
+pub fn lines(cli: &Cli, stats: &[Stats]) -> Vec<String>
+{
+  let mut vec : Vec<String> = Vec::with_capacity(stats.len() * 9);
+  let host = sanitise(&cli.host);
+
+  for stat in stats
+  {
+    let container = sanitise(&stat.container);
+    let name = sanitise(&stat.name);
+    let id = sanitise(&stat.id);
+    let timestamp = stat.time.timestamp();
+
+    for (metric, value) in values(stat).into_iter().filter(|(_, v)| v.is_finite())
+    {
+      let path = if cli.graphite_tagged
+      {
+        let prefix = if cli.graphite_prefix.is_empty() { String::new() } else { format!("{}.", cli.graphite_prefix) };
+        format!("{}{};host={};container={};name={}", prefix, metric, host, container, name)
+      }
+      else
+      {
+        let path = cli.graphite_template.
+            replace("{prefix}", &cli.graphite_prefix).
+            replace("{host}", &host).
+            replace("{container}", &container).
+            replace("{name}", &name).
+            replace("{id}", &id).
+            replace("{metric}", metric);
+        path.split('.').filter(|p| !p.is_empty()).collect::<Vec<&str>>().join(".")
+      };
+
+      vec.push(format!("{} {} {}", path, value, timestamp));
+    }
+  }
+
+  vec
+}
+
+pub fn publish(cli: &Cli, stats: &[Stats]) -> Result<(), String>
+{
+  let address = match &cli.graphite { Some(address) => address, None => return Ok(()) };
+
+  let addr = address.to_socket_addrs().map_err(|e| format!("Invalid Graphite address {}. {}", address, e))?.
+      next().ok_or(format!("Unable to resolve Graphite address {}", address))?;
+  let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|e| format!("Failed to connect to Graphite {}. {}", address, e))?;
+  stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+
+  let mut payload = lines(cli, stats).join("\n");
+  payload.push('\n');
+  stream.write_all(payload.as_bytes()).map_err(|e| format!("Failed to write to Graphite {}. {}", address, e))?;
+  stream.flush().map_err(|e| format!("Failed to write to Graphite {}. {}", address, e))?;
+  agent::sent(payload.len());
+
+  info!("Published {:?} container statistics for {} to {}.", stats.len(), cli.host, address);
+  Ok(())
+}
+
+/// Writes to a Graphite/carbon plaintext listener.
+pub struct Graphite
+{
+  cli: Cli
+}
+
+impl Graphite
+{
+  pub fn new(cli: &Cli) -> Graphite
+  {
+    Graphite{cli: cli.clone()}
+  }
+}
+
+impl Sink for Graphite
+{
+  fn name(&self) -> String
+  {
+    format!("Graphite {}", self.cli.graphite.as_deref().unwrap_or_default())
+  }
+
+  fn publish(&mut self, batch: &Batch) -> Result<(), String>
+  {
+    publish(&self.cli, &batch.stats)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use chrono::{DateTime, TimeDelta};
+
+  use super::*;
+  use super::super::cli;
+
+  fn stat() -> Stats
+  {
+    let mut stat = Stats::new();
+    stat.id = "3f2a1b".to_string();
+    stat.container = "3f2a1b".to_string();
+    stat.name = "web.1 a/b".to_string();
+    stat.cpuPercentage = 1.5;
+    stat.pids = 12;
+    stat.time = DateTime::UNIX_EPOCH + TimeDelta::seconds(60);
+    stat
+  }
+
+  #[test]
+  fn sanitise_replaces_path_separators()
+  {
+    assert_eq!(sanitise("web.1 a/b;c=d~e\\f\tg\nh"), "web_1_a_b_c_d_e_f_g_h");
+    assert_eq!(sanitise(""), "_");
+  }
+
+  #[test]
+  fn template_paths_are_substituted()
+  {
+    let paths = lines(&cli(&["--node", "node.1", "--graphite-prefix", "containers"]), &[stat()]);
+    assert_eq!(paths.len(), 9);
+    assert_eq!(paths[0], "containers.node_1.web_1_a_b.cpu 1.5 60");
+    assert_eq!(paths[4], "containers.node_1.web_1_a_b.pids 12 60");
+
+    // Empty components left by an empty prefix are dropped.
+    let cli = cli(&["--node", "node1", "--graphite-prefix", "", "--graphite-template", "{prefix}.{host}.{container}.{id}.{metric}"]);
+    assert_eq!(lines(&cli, &[stat()])[0], "node1.3f2a1b.3f2a1b.cpu 1.5 60");
+  }
+
+  #[test]
+  fn tagged_series_carry_the_container()
+  {
+    let tagged = lines(&cli(&["--node", "node 1", "--graphite-tagged", "--graphite-prefix", "containers"]), &[stat()]);
+    assert_eq!(tagged[0], "containers.cpu;host=node_1;container=3f2a1b;name=web_1_a_b 1.5 60");
+    let tagged = lines(&cli(&["--node", "node1", "--graphite-tagged", "--graphite-prefix", ""]), &[stat()]);
+    assert_eq!(tagged[0], "cpu;host=node1;container=3f2a1b;name=web_1_a_b 1.5 60");
+  }
+
+  #[test]
+  fn values_that_are_not_finite_are_left_out()
+  {
+    let mut stat = stat();
+    stat.cpuPercentage = f64::NAN;
+    stat.memoryPercentage = f64::INFINITY;
+    let finite = lines(&cli(&[]), &[stat]);
+    assert_eq!(finite.len(), 7);
+    assert!(finite.iter().all(|l| !l.contains("NaN") && !l.contains("inf")));
+  }
+}
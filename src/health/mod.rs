@@ -0,0 +1,79 @@
+use std::io::{Error, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use chrono::{TimeDelta, Utc};
+use log::{info, warn};
+use serde_json::json;
+use tiny_http::{Header, Response, Server};
+
+use super::agent::{self, Status};
+
+/// Whether the collection loop has made progress within the timeout.  Before the first
+/// collection, the time since the agent started is used.
+fn healthy(status: &Status, timeout: u64) -> bool
+{
+  let last = status.last_collection.unwrap_or(status.started);
+  Utc::now() - last <= TimeDelta::seconds(timeout as i64)
+}
+
+/// Whether the last publish was accepted by all the outputs.  Ready until the first publish.
+fn ready(status: &Status) -> bool
+{
+  status.last_publish_succeeded.unwrap_or(true)
+}
+
+fn respond(path: &str, timeout: u64) -> (u16, String)
+{
+  let status = agent::status();
+  let code = |ok: bool| if ok { 200 } else { 503 };
+  match path
+  {
+    "/healthz" =>
+    {
+      let ok = healthy(&status, timeout);
+      (code(ok), json!({"healthy": ok, "last_collection": status.last_collection}).to_string())
+    },
+    "/readyz" =>
+    {
+      let ok = ready(&status);
+      (code(ok), json!({"ready": ok, "last_publish": status.last_publish}).to_string())
+    },
+    "/status" =>
+    {
+      let mut value = serde_json::to_value(&status).unwrap_or_default();
+      value["healthy"] = json!(healthy(&status, timeout));
+      value["ready"] = json!(ready(&status));
+      (200, value.to_string())
+    },
+    _ => (404, json!({"error": format!("{} not found", path)}).to_string())
+  }
+}
+
+/// Serve `/healthz`, `/readyz` and `/status` on the address until terminated.
+pub fn serve(address: &str, timeout: u64, term: Arc<AtomicBool>) -> Result<JoinHandle<()>>
+{
+  let server = Server::http(address).map_err(Error::other)?;
+  info!("Serving health checks on {}", address);
+
+  Ok(thread::spawn(move ||
+      {
+        let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+        while !term.load(Ordering::Relaxed)
+        {
+          match server.recv_timeout(Duration::from_secs(1))
+          {
+            Ok(Some(request)) =>
+            {
+              let path = request.url().split('?').next().unwrap_or_default().to_string();
+              let (code, body) = respond(&path, timeout);
+              let response = Response::from_string(body).with_status_code(code).with_header(header.clone());
+              if let Err(e) = request.respond(response) { warn!("Error responding to health check {}. {}", path, e); }
+            },
+            Ok(None) => continue,
+            Err(e) => warn!("Error receiving health check. {}", e)
+          }
+        }
+      }))
+}
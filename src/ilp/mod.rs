@@ -1,26 +1,39 @@
 use std::collections::HashMap;
-use std::ffi::{OsString};
-use sysinfo::Disks;
+use std::io::Write;
 use chrono::{DateTime, Utc};
 use float_ord::sort;
 use log::info;
-use questdb::{
-  Result,
-  ingress::{
-    Buffer,
-    ColumnName,
-    Sender,
-    TableName,
-    TimestampNanos
-  },
+use questdb::ingress::{
+  Buffer,
+  ColumnName,
+  Sender,
+  TableName,
+  TimestampNanos
 };
 
 use super::{Cli, Mode};
-use super::stats::{Measurement, Stats, IO};
+use super::agent::{self, AgentStats};
+use super::error::{Error, Result};
+use super::listener::{Kind, Metric};
+use super::sink::{Batch, Sink};
+use super::stats::{DiskStats, Measurement, Stats, IO};
 
-pub fn gather(cli: &Cli, stats: Vec<Stats>) -> Vec<Stats>
+/// The weight of each of a container's samples when averaging: the time since its previous
+/// sample, so that a sample taken after a gap counts for the whole gap.  The first sample is
+/// weighted like the second.  Samples are weighted equally when they share a timestamp.
+fn weights(times: &[DateTime<Utc>]) -> Vec<f64>
 {
-  info!("Aggregating {:?} container statistics for {}.", stats.len(), cli.host);
+  let mut weights : Vec<f64> = times.windows(2).map(|w| (w[1] - w[0]).num_milliseconds().max(0) as f64).collect();
+  weights.insert(0, weights.first().copied().unwrap_or(1.0));
+  if weights.iter().sum::<f64>() <= 0.0 { return vec![1.0; times.len()]; }
+  weights
+}
+
+pub fn gather(mode: Mode, stats: Vec<Stats>, time: DateTime<Utc>) -> Vec<Stats>
+{
+  if mode == Mode::Raw { return stats; }
+
+  info!("Aggregating {:?} container statistics.", stats.len());
   let mut cpu : HashMap<String, Vec<f64>> = HashMap::new();
   let mut mem : HashMap<String, Vec<f64>> = HashMap::new();
   let mut memper : HashMap<String, Vec<f64>> = HashMap::new();
@@ -29,9 +42,12 @@ pub fn gather(cli: &Cli, stats: Vec<Stats>) -> Vec<Stats>
   let mut netin : HashMap<String, Vec<f64>> = HashMap::new();
   let mut netout : HashMap<String, Vec<f64>> = HashMap::new();
   let mut pids : HashMap<String, Vec<u32>> = HashMap::new();
+  let mut times : HashMap<String, Vec<DateTime<Utc>>> = HashMap::new();
 
   for stat in &stats
   {
+    times.entry(stat.name.clone()).or_default().push(stat.time);
+
     if cpu.contains_key(&stat.name) { cpu.get_mut(&stat.name).unwrap().push(stat.cpuPercentage); }
     else { cpu.insert(stat.name.clone(), vec![stat.cpuPercentage]); }
 
@@ -58,90 +74,375 @@ pub fn gather(cli: &Cli, stats: Vec<Stats>) -> Vec<Stats>
   }
 
   let mut vec : Vec<Stats> = Vec::with_capacity(32);
+  let weights : HashMap<String, Vec<f64>> = times.iter().map(|(name, times)| (name.clone(), weights(times))).collect();
 
-  fn compute(mode: Mode, values: &mut Vec<f64>) -> f64
+  fn compute(mode: Mode, values: &mut [f64], weights: &[f64]) -> f64
   {
-    if mode == Mode::Avg { return values.iter().sum::<f64>() / (values.len() as f64); }
+    if mode == Mode::Avg { return values.iter().zip(weights).map(|(v, w)| v * w).sum::<f64>() / weights.iter().sum::<f64>(); }
     sort(values);
     values[values.len() - 1]
   }
 
-  fn compute_pids(mode: Mode, values: &Vec<u32>) -> u32
+  fn compute_pids(mode: Mode, values: &[u32], weights: &[f64]) -> u32
   {
-    if mode == Mode::Avg { return values.iter().sum::<u32>() / (values.len() as u32); }
+    if mode == Mode::Avg { return (values.iter().zip(weights).map(|(v, w)| *v as f64 * w).sum::<f64>() / weights.iter().sum::<f64>()) as u32; }
     *values.iter().max().unwrap()
   }
 
   for (name, values) in &mut cpu
   {
+    let first = stats.iter().find(|s| s.name == *name).unwrap();
     let mut st = Stats::new();
     st.name = name.clone();
-    st.id = stats[0].id.clone();
-    st.container = stats[0].container.clone();
-    st.totalMemory.value = stats[0].totalMemory.value;
-    st.totalMemory.unit = stats[0].totalMemory.unit.clone();
-    st.cpuPercentage = compute(cli.mode, values);
+    st.time = time;
+    st.id = first.id.clone();
+    st.container = first.container.clone();
+    st.labels = first.labels.clone();
+    st.totalMemory.value = first.totalMemory.value;
+    st.totalMemory.unit = first.totalMemory.unit.clone();
+    st.memoryUsage.unit = first.memoryUsage.unit.clone();
+    st.blockIO.incoming.unit = first.blockIO.incoming.unit.clone();
+    st.blockIO.outgoing.unit = first.blockIO.outgoing.unit.clone();
+    st.netIO.incoming.unit = first.netIO.incoming.unit.clone();
+    st.netIO.outgoing.unit = first.netIO.outgoing.unit.clone();
+    st.cpuPercentage = compute(mode, values, &weights[name]);
     vec.push(st);
   }
 
   for (name, values) in &mut mem
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.memoryUsage.unit = stats[0].memoryUsage.unit.clone();
-    stat.memoryUsage.value = compute(cli.mode, values);
+    stat.memoryUsage.value = compute(mode, values, &weights[name]);
   }
 
   for (name, values) in &mut memper
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.memoryPercentage = compute(cli.mode, values);
+    stat.memoryPercentage = compute(mode, values, &weights[name]);
   }
 
   for (name, values) in &mut bioin
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.blockIO.incoming.unit = stats[0].blockIO.incoming.unit.clone();
-    stat.blockIO.incoming.value = compute(cli.mode, values);
+    stat.blockIO.incoming.value = compute(mode, values, &weights[name]);
   }
 
   for (name, values) in &mut bioout
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.blockIO.outgoing.unit = stats[0].blockIO.outgoing.unit.clone();
-    stat.blockIO.outgoing.value = compute(cli.mode, values);
+    stat.blockIO.outgoing.value = compute(mode, values, &weights[name]);
   }
 
   for (name, values) in &mut netin
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.netIO.incoming.unit = stats[0].netIO.incoming.unit.clone();
-    stat.netIO.incoming.value = compute(cli.mode, values);
+    stat.netIO.incoming.value = compute(mode, values, &weights[name]);
   }
 
   for (name, values) in &mut netout
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.netIO.outgoing.unit = stats[0].netIO.outgoing.unit.clone();
-    stat.netIO.outgoing.value = compute(cli.mode, values);
+    stat.netIO.outgoing.value = compute(mode, values, &weights[name]);
   }
 
   for (name, values) in &pids
   {
     let stat = vec.iter_mut().find(|s| s.name == *name).unwrap();
-    stat.pids = compute_pids(cli.mode, values);
+    stat.pids = compute_pids(mode, values, &weights[name]);
   }
 
   vec
 }
 
-pub fn publish(cli: &Cli, stats: Vec<Stats>, time: DateTime<Utc>) -> Result<()>
+/// The container statistics columns, which label symbols are prefixed to avoid.
+const STATS_COLUMNS: [&str; 15] = ["host", "container", "name", "id", "cpu", "memory_percentage", "pids", "block_io_in", "block_io_out",
+  "net_io_in", "net_io_out", "memory_use", "total_memory", "coverage", "timestamp"];
+
+/// The symbol column for each `--label-symbol` key.  Characters other than letters, digits and
+/// `_` are replaced with `_`, and a column that clashes with a statistics column is prefixed with
+/// `label_`.  Column names are case insensitive, so keys that map to the same column are rejected.
+pub fn label_columns(keys: &[String]) -> Result<Vec<(String, String)>>
+{
+  let mut columns : Vec<(String, String)> = Vec::with_capacity(keys.len());
+  for key in keys
+  {
+    if columns.iter().any(|(k, _)| k == key) { continue; }
+    let mut column : String = key.chars().map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    if STATS_COLUMNS.contains(&column.to_lowercase().as_str()) { column = format!("label_{}", column); }
+    if let Some((other, _)) = columns.iter().find(|(_, c)| c.eq_ignore_ascii_case(&column))
+    {
+      return Err(Error::Config(format!("Labels {} and {} both map to the column {}", other, key, column)));
+    }
+    ColumnName::new(column.as_str()).map_err(|e| Error::Config(format!("Label {} does not map to a valid column. {}", key, e)))?;
+    columns.push((key.clone(), column));
+  }
+  Ok(columns)
+}
+
+/// A QuestDB instance to publish to, with the table names to use.
+#[derive(Clone, Debug)]
+pub struct Destination
 {
-  let uri = format!("{:?}::addr={}:{}", cli.protocol, cli.questdb, cli.port);
-  info!("Publishing {:?} container statistics for {} to {}.", stats.len(), cli.host, uri);
+  /// Optional label used when logging.  Defaults to the address.
+  pub name: String,
+  /// The QuestDB client configuration string, e.g. `http::addr=localhost:9000;username=admin;`
+  pub conf: String,
+  pub stats_table: String,
+  pub disk_table: String,
+  pub app_table: String,
+  pub agent_table: String
+}
+
+impl Destination
+{
+  /// The destination specified by the `--questdb`, `--port` and `--transport-protocol` options.
+  pub fn new(cli: &Cli) -> Destination
+  {
+    let address = format!("{}:{}", cli.questdb, cli.port);
+    Destination{name: address.clone(), conf: format!("{:?}::addr={};", cli.protocol, address),
+      stats_table: cli.table.clone(), disk_table: cli.disk_table.clone(), app_table: cli.app_table.clone(),
+      agent_table: cli.agent_table.clone()}
+  }
+}
+
+/// Parse a `--destination`.  This is a QuestDB client configuration string, with optional
+/// `name`, `stats_table`, `disk_table`, `app_table` and `agent_table` parameters that are removed before the
+/// remainder is passed to the client.  Table names default to the corresponding options.
+pub fn destination(value: &str) -> std::result::Result<Destination, String>
+{
+  let (protocol, params) = value.split_once("::").ok_or(format!("Invalid destination {}, expected protocol::key=value;...", value))?;
+  if !["tcp", "tcps", "http", "https"].contains(&protocol) { return Err(format!("Invalid destination protocol {}", protocol)); }
+
+  let mut dest = Destination{name: String::new(), conf: format!("{}::", protocol),
+    stats_table: String::new(), disk_table: String::new(), app_table: String::new(), agent_table: String::new()};
+
+  // Values escape `;` by doubling it.
+  let mut pairs : Vec<(String, String)> = Vec::new();
+  let mut chars = params.chars().peekable();
+  let mut current = String::new();
+  while let Some(c) = chars.next()
+  {
+    if c == ';'
+    {
+      if chars.peek() == Some(&';') { chars.next(); current.push(';'); continue; }
+      if !current.is_empty()
+      {
+        let (k, v) = current.split_once('=').ok_or(format!("Invalid destination parameter {}", current))?;
+        pairs.push((k.trim().to_string(), v.to_string()));
+      }
+      current.clear();
+    }
+    else { current.push(c); }
+  }
+  if !current.is_empty()
+  {
+    let (k, v) = current.split_once('=').ok_or(format!("Invalid destination parameter {}", current))?;
+    pairs.push((k.trim().to_string(), v.to_string()));
+  }
+
+  for (key, value) in pairs
+  {
+    match key.as_str()
+    {
+      "name" => dest.name = value,
+      "stats_table" => dest.stats_table = value,
+      "disk_table" => dest.disk_table = value,
+      "app_table" => dest.app_table = value,
+      "agent_table" => dest.agent_table = value,
+      _ =>
+      {
+        if key == "addr" && dest.name.is_empty() { dest.name = value.clone(); }
+        dest.conf.push_str(&format!("{}={};", key, value.replace(';', ";;")));
+      }
+    }
+  }
+
+  if !dest.conf.contains("addr=") { return Err(format!("Destination {} does not specify addr", value)); }
+  Ok(dest)
+}
+
+/// The configured destinations.  The `--questdb` host is used if no `--destination` is specified.
+pub fn destinations(cli: &Cli) -> Vec<Destination>
+{
+  if cli.destinations.is_empty() { return vec![Destination::new(cli)]; }
+
+  cli.destinations.iter().map(|d|
+      {
+        let mut dest = d.clone();
+        if dest.stats_table.is_empty() { dest.stats_table = cli.table.clone(); }
+        if dest.disk_table.is_empty() { dest.disk_table = cli.disk_table.clone(); }
+        if dest.app_table.is_empty() { dest.app_table = cli.app_table.clone(); }
+        if dest.agent_table.is_empty() { dest.agent_table = cli.agent_table.clone(); }
+        dest
+      }).collect()
+}
+
+/// Publishes to a QuestDB destination over ILP.
+pub struct QuestDB
+{
+  cli: Cli,
+  destination: Destination
+}
+
+impl QuestDB
+{
+  pub fn new(cli: &Cli, destination: Destination) -> QuestDB
+  {
+    QuestDB{cli: cli.clone(), destination}
+  }
+}
+
+impl Sink for QuestDB
+{
+  fn name(&self) -> String
+  {
+    format!("QuestDB {}", self.destination.name)
+  }
+
+  fn publish(&mut self, batch: &Batch) -> std::result::Result<(), String>
+  {
+    publish(&self.cli, &self.destination, batch).map_err(|e| e.to_string())
+  }
+}
+
+/// Renders the ILP payload that would be sent to a QuestDB destination to stdout, without
+/// connecting to it.
+pub struct DryRun
+{
+  cli: Cli,
+  destination: Destination
+}
+
+impl DryRun
+{
+  pub fn new(cli: &Cli, destination: Destination) -> DryRun
+  {
+    DryRun{cli: cli.clone(), destination}
+  }
+}
+
+impl Sink for DryRun
+{
+  fn name(&self) -> String
+  {
+    format!("Dry run QuestDB {}", self.destination.name)
+  }
+
+  fn publish(&mut self, batch: &Batch) -> std::result::Result<(), String>
+  {
+    let buffer = buffer(&self.cli, &self.destination, batch).map_err(|e| e.to_string())?;
+    let mut out = format!("# Dry run for QuestDB {} at {}: {} rows, {} bytes\n", self.destination.name, batch.time.to_rfc3339(), buffer.row_count(), buffer.len());
+    out.push_str(&describe(buffer.as_str()));
+    out.push_str(buffer.as_str());
+
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(out.as_bytes()).and_then(|_| stdout.flush()).map_err(|e| e.to_string())
+  }
+}
+
+/// Split ILP text on the separator, ignoring escaped characters and separators within quotes.
+fn split_ilp(value: &str, separator: char) -> Vec<&str>
+{
+  let mut parts : Vec<&str> = Vec::new();
+  let mut start = 0;
+  let mut escaped = false;
+  let mut quoted = false;
+  for (i, c) in value.char_indices()
+  {
+    if escaped { escaped = false; continue; }
+    match c
+    {
+      '\\' => escaped = true,
+      '"' => quoted = !quoted,
+      c if c == separator && !quoted =>
+      {
+        parts.push(&value[start..i]);
+        start = i + c.len_utf8();
+      },
+      _ => {}
+    }
+  }
+  parts.push(&value[start..]);
+  parts
+}
+
+/// The symbols and column types seen for a table.
+struct TableSummary
+{
+  name: String,
+  symbols: Vec<String>,
+  columns: Vec<(String, &'static str)>
+}
+
+/// Summarise the tables, symbols and column types in the ILP text, as `#` comment lines.
+fn describe(ilp: &str) -> String
+{
+  let mut tables : Vec<TableSummary> = Vec::new();
+  for line in ilp.lines()
+  {
+    let sections = split_ilp(line, ' ');
+    if sections.len() < 2 { continue; }
+
+    let mut series = split_ilp(sections[0], ',').into_iter();
+    let table = series.next().unwrap_or_default().to_string();
+    let index = match tables.iter().position(|t| t.name == table)
+    {
+      Some(i) => i,
+      None => { tables.push(TableSummary{name: table, symbols: Vec::new(), columns: Vec::new()}); tables.len() - 1 }
+    };
+    let entry = &mut tables[index];
+
+    for symbol in series
+    {
+      let name = symbol.split('=').next().unwrap_or_default().to_string();
+      if !entry.symbols.contains(&name) { entry.symbols.push(name); }
+    }
 
-  let mut sender = Sender::from_conf(uri)?;
+    for field in split_ilp(sections[1], ',')
+    {
+      let Some((name, value)) = field.split_once('=') else { continue; };
+      // Numbers with a type suffix, e.g. `-5i` or `1700000000000000t`.
+      let suffixed = |suffix: char, signed: bool| value.strip_suffix(suffix).
+          map(|v| if signed { v.strip_prefix('-').unwrap_or(v) } else { v }).
+          is_some_and(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()));
+      let kind = if value.starts_with('"') { "string" }
+        else if ["t", "T", "f", "F", "true", "false", "True", "False", "TRUE", "FALSE"].contains(&value) { "boolean" }
+        else if suffixed('i', true) { "long" }
+        else if suffixed('t', false) || suffixed('n', false) { "timestamp" }
+        else { "double" };
+      if !entry.columns.iter().any(|c| c.0 == name) { entry.columns.push((name.to_string(), kind)); }
+    }
+  }
+
+  let mut out = String::new();
+  for table in tables
+  {
+    out.push_str(&format!("# table {}\n#   symbols: {}\n#   columns: {}\n", table.name, table.symbols.join(", "),
+      table.columns.iter().map(|(n, k)| format!("{} {}", n, k)).collect::<Vec<String>>().join(", ")));
+  }
+  out
+}
+
+pub fn publish(cli: &Cli, destination: &Destination, batch: &Batch) -> Result<()>
+{
+  info!("Publishing {:?} container statistics for {} to {}.", batch.stats.len(), cli.host, destination.name);
+
+  let mut buffer = buffer(cli, destination, batch)?;
+  let mut sender = Sender::from_conf(&destination.conf)?;
+  let bytes = buffer.len();
+  sender.flush(&mut buffer)?;
+  agent::sent(bytes);
+  info!("Published {:?} container statistics for {} to {}.", batch.stats.len(), cli.host, destination.name);
+  Ok(())
+}
+
+/// Build the ILP rows for the batch.
+pub fn buffer(cli: &Cli, destination: &Destination, batch: &Batch) -> Result<Buffer>
+{
+  let stats = &batch.stats;
+  let time = batch.time;
   let mut buffer = Buffer::new();
-  let table = TableName::new(cli.table.as_str())?;
+  let table = TableName::new(destination.stats_table.as_str())?;
   
   let chost = ColumnName::new("host")?;
   let ccontainer = ColumnName::new("container")?;
@@ -150,60 +451,57 @@ pub fn publish(cli: &Cli, stats: Vec<Stats>, time: DateTime<Utc>) -> Result<()>
   let ccpu = ColumnName::new("cpu")?;
   let cmp = ColumnName::new("memory_percentage")?;
   let cpids = ColumnName::new("pids")?;
+  let ccoverage = ColumnName::new("coverage")?;
   
-  fn add_io(buffer: &mut Buffer, io: &IO, prefix: &str)
+  fn add_io(buffer: &mut Buffer, io: &IO, prefix: &str) -> Result<()>
   {
-    fn bytes(measurement: &Measurement) -> f64
-    {
-      if measurement.unit == "KB" { return measurement.value * 1024.0; }
-      else if measurement.unit == "MB" { return measurement.value * 1024.0 * 1024.0; }
-      else if measurement.unit == "GB" { return measurement.value * 1024.0 * 1024.0 * 1024.0; }
-      measurement.value
-    }
-    
-    buffer.column_f64(format!("{}_in", prefix).as_str(), bytes(&io.incoming)).expect(format!("Failed to add incoming {} IO", prefix).as_str());
-    buffer.column_f64(format!("{}_out", prefix).as_str(), bytes(&io.outgoing)).expect(format!("Failed to add outgoing {} IO", prefix).as_str());
+    buffer.column_f64(format!("{}_in", prefix).as_str(), io.incoming.bytes())?;
+    buffer.column_f64(format!("{}_out", prefix).as_str(), io.outgoing.bytes())?;
+    Ok(())
   }
   
-  fn add_memory(buffer: &mut Buffer, measurement: &Measurement, column: &str)
+  fn add_memory(buffer: &mut Buffer, measurement: &Measurement, column: &str) -> Result<()>
   {
-    if measurement.unit == "B" { buffer.column_f64(column, measurement.value).expect("Failed to add memory B"); }
-    if measurement.unit == "KiB" { buffer.column_f64(column, measurement.value * 1024.0).expect("Failed to add memory KiB"); }
-    if measurement.unit == "MiB" { buffer.column_f64(column, measurement.value * 1024.0 * 1024.0).expect("Failed to add memory MiB"); }
-    if measurement.unit == "GiB" { buffer.column_f64(column, measurement.value * 1024.0 * 1024.0 * 1024.0).expect("Failed to add memory GiB"); }
+    buffer.column_f64(column, measurement.bytes())?;
+    Ok(())
   }
   
-  for stat in &stats
+  let labels = label_columns(&cli.label_symbols)?;
+  for stat in stats
   {
     buffer.table(table)?.
         symbol(chost, cli.host.clone())?.
         symbol(ccontainer, stat.container.clone())?.
-        symbol(cname, stat.name.clone())?.
-        column_str(cid, stat.id.clone())?.
+        symbol(cname, stat.name.clone())?;
+    for (key, column) in &labels
+    {
+      if let Some(value) = stat.labels.get(key) { buffer.symbol(column.as_str(), value.as_str())?; }
+    }
+    buffer.column_str(cid, stat.id.clone())?.
         column_f64(ccpu, stat.cpuPercentage)?.
         column_f64(cmp, stat.memoryPercentage)?.
         column_i64(cpids, stat.pids as i64)?;
     
-    add_io(&mut buffer, &stat.blockIO, "block_io");
-    add_io(&mut buffer, &stat.netIO, "net_io");
-    add_memory(&mut buffer, &stat.memoryUsage, "memory_use");
-    add_memory(&mut buffer, &stat.totalMemory, "total_memory");
+    add_io(&mut buffer, &stat.blockIO, "block_io")?;
+    add_io(&mut buffer, &stat.netIO, "net_io")?;
+    add_memory(&mut buffer, &stat.memoryUsage, "memory_use")?;
+    add_memory(&mut buffer, &stat.totalMemory, "total_memory")?;
+    if let Some(coverage) = batch.partial() { buffer.column_f64(ccoverage, coverage)?; }
     
-    buffer.at(TimestampNanos::from_datetime(time)?)?;
+    buffer.at(TimestampNanos::from_datetime(stat.time)?)?;
   }
   
-  disk_usage(cli, &mut buffer, time)?;
-
-  sender.flush(&mut buffer)?;
-  info!("Published {:?} container statistics for {}.", stats.len(), cli.host);
-  Ok(())
+  disk_usage(cli, &destination.disk_table, &mut buffer, &batch.disks, time)?;
+  app_metrics(cli, &destination.app_table, &mut buffer, &batch.metrics, batch.duration, time)?;
+  agent_stats(cli, &destination.agent_table, &mut buffer, &batch.agent, time)?;
+  Ok(buffer)
 }
 
-fn disk_usage(cli: &Cli, buf: &mut Buffer, time: DateTime<Utc>) -> Result<()>
+fn disk_usage(cli: &Cli, table: &str, buf: &mut Buffer, disks: &[DiskStats], time: DateTime<Utc>) -> Result<()>
 {
-  if cli.disks.is_empty() { return Ok(()); }
+  if disks.is_empty() { return Ok(()); }
 
-  let table = TableName::new(cli.disk_table.as_str())?;
+  let table = TableName::new(table)?;
   let chost = ColumnName::new("host")?;
   let cname = ColumnName::new("name")?;
   let cfs = ColumnName::new("file_system")?;
@@ -214,28 +512,235 @@ fn disk_usage(cli: &Cli, buf: &mut Buffer, time: DateTime<Utc>) -> Result<()>
   let cr = ColumnName::new("read_bytes")?;
   let cw = ColumnName::new("write_bytes")?;
   
-  let disks = Disks::new_with_refreshed_list();
-  for disk in disks.list()
+  for disk in disks
   {
-    for name in &cli.disks
+    buf.table(table)?.
+        symbol(chost, cli.host.clone())?.
+        symbol(cname, disk.name.as_str())?.
+        symbol(cfs, disk.fileSystem.as_str())?.
+        symbol(cmp, disk.mountPoint.as_str())?.
+        symbol(ctype, disk.kind.as_str())?.
+        column_i64(cas, disk.availableSpace as i64)?.
+        column_f64(cpu, disk.percentage())?.
+        column_i64(cr, disk.readBytes as i64)?.
+        column_i64(cw, disk.writtenBytes as i64)?.
+        at(TimestampNanos::from_datetime(time)?)?;
+    info!("Added disk statistics for {} on {}.", disk.name, cli.host);
+  }
+  
+  Ok(())
+}
+
+/// Write the application metrics.  Counter rates are per second over the batch's duration, and
+/// are omitted when it is not known.
+fn app_metrics(cli: &Cli, table: &str, buf: &mut Buffer, metrics: &[Metric], duration: f64, time: DateTime<Utc>) -> Result<()>
+{
+  if metrics.is_empty() { return Ok(()); }
+
+  let table = TableName::new(table)?;
+  let chost = ColumnName::new("host")?;
+  let cmetric = ColumnName::new("metric")?;
+  let ctype = ColumnName::new("type")?;
+  let ctags = ColumnName::new("tags")?;
+  let csamples = ColumnName::new("samples")?;
+  let cvalue = ColumnName::new("value")?;
+  let crate_ = ColumnName::new("rate")?;
+
+  for metric in metrics
+  {
+    buf.table(table)?.
+        symbol(chost, cli.host.as_str())?.
+        symbol(cmetric, metric.name.as_str())?.
+        symbol(ctype, metric.kind.name())?;
+    if !metric.tags.is_empty() { buf.symbol(ctags, metric.tags.as_str())?; }
+    buf.column_i64(csamples, metric.samples as i64)?.
+        column_f64(cvalue, metric.value)?;
+
+    if metric.kind == Kind::Counter && duration > 0.0 { buf.column_f64(crate_, metric.value / duration)?; }
+    if let Some(summary) = &metric.summary
     {
-      if disk.name() ==  OsString::from(name)
-      {
-        buf.table(table)?.
-            symbol(chost, cli.host.clone())?.
-            symbol(cname, disk.name().to_str().unwrap().to_string())?.
-            symbol(cfs, disk.file_system().to_str().unwrap().to_string())?.
-            symbol(cmp, disk.mount_point().to_str().unwrap().to_string())?.
-            symbol(ctype, disk.kind().to_string())?.
-            column_i64(cas, disk.available_space() as i64)?.
-            column_f64(cpu, (disk.available_space() as f64)/(disk.total_space() as f64) * 100.0)?.
-            column_i64(cr, disk.usage().read_bytes as i64)?.
-            column_i64(cw, disk.usage().written_bytes as i64)?.
-            at(TimestampNanos::from_datetime(time)?)?;
-        info!("Added disk statistics for {} on {}.", name, cli.host);
-      }
+      buf.column_f64("count", summary.count)?.
+          column_f64("sum", summary.sum)?.
+          column_f64("min", summary.min)?.
+          column_f64("max", summary.max)?.
+          column_f64("mean", summary.mean)?.
+          column_f64("median", summary.median)?.
+          column_f64("p90", summary.p90)?.
+          column_f64("p95", summary.p95)?.
+          column_f64("p99", summary.p99)?;
     }
+
+    buf.at(TimestampNanos::from_datetime(time)?)?;
   }
-  
+
+  info!("Added {} application metrics for {}.", metrics.len(), cli.host);
   Ok(())
-}
\ No newline at end of file
+}
+
+fn agent_stats(cli: &Cli, table: &str, buf: &mut Buffer, agent: &AgentStats, time: DateTime<Utc>) -> Result<()>
+{
+  buf.table(table)?.
+      symbol("host", cli.host.as_str())?.
+      column_i64("collections", agent.collections as i64)?.
+      column_f64("collection_min_ms", agent.collection_min)?.
+      column_f64("collection_avg_ms", agent.collection_avg)?.
+      column_f64("collection_max_ms", agent.collection_max)?.
+      column_i64("records_parsed", agent.parsed as i64)?.
+      column_i64("records_rejected", agent.rejected as i64)?.
+      column_i64("samples_skipped", agent.skipped as i64)?.
+      column_i64("collection_timeouts", agent.timeouts as i64)?.
+      column_f64("publish_ms", agent.publish)?.
+      column_i64("bytes_sent", agent.bytes as i64)?.
+      column_i64("retries", agent.retries as i64)?.
+      column_i64("failures", agent.failures as i64)?.
+      column_i64("spool_depth", agent.spool)?.
+      column_i64("rss", agent.rss as i64)?.
+      column_f64("cpu", agent.cpu)?.
+      at(TimestampNanos::from_datetime(time)?)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+  use chrono::TimeDelta;
+
+  use super::*;
+
+  fn stat(id: &str, name: &str, cpu: f64, limit: f64, seconds: i64) -> Stats
+  {
+    let mut stat = Stats::new();
+    stat.id = id.to_string();
+    stat.container = id.to_string();
+    stat.name = name.to_string();
+    stat.cpuPercentage = cpu;
+    stat.totalMemory = Measurement{value: limit, unit: "MiB".to_string()};
+    stat.time = DateTime::UNIX_EPOCH + TimeDelta::seconds(seconds);
+    stat
+  }
+
+  #[test]
+  fn gather_keeps_each_containers_identity()
+  {
+    let stats = vec![stat("bbb", "b", 1.0, 512.0, 0), stat("aaa", "a", 2.0, 1024.0, 0), stat("bbb", "b", 3.0, 512.0, 5),
+      stat("aaa", "a", 4.0, 1024.0, 5)];
+    let mut rows = gather(Mode::Max, stats, Utc::now());
+    rows.sort_by(|a, b| a.name.cmp(&b.name));
+
+    assert_eq!(rows.len(), 2);
+    assert_eq!((rows[0].id.as_str(), rows[0].container.as_str(), rows[0].totalMemory.value), ("aaa", "aaa", 1024.0));
+    assert_eq!((rows[1].id.as_str(), rows[1].container.as_str(), rows[1].totalMemory.value), ("bbb", "bbb", 512.0));
+    assert_eq!((rows[0].cpuPercentage, rows[1].cpuPercentage), (4.0, 3.0));
+  }
+
+  #[test]
+  fn weights_follow_the_sample_spacing()
+  {
+    let times : Vec<DateTime<Utc>> = [0, 10, 40].iter().map(|s| DateTime::UNIX_EPOCH + TimeDelta::seconds(*s)).collect();
+    assert_eq!(weights(&times), vec![10000.0, 10000.0, 30000.0]);
+    assert_eq!(weights(&times[..1]), vec![1.0]);
+    assert_eq!(weights(&[DateTime::UNIX_EPOCH; 3]), vec![1.0, 1.0, 1.0]);
+  }
+
+  #[test]
+  fn averages_are_weighted_by_the_sample_spacing()
+  {
+    // The sample after the 30s gap counts for three times as long as the others.
+    let stats = vec![stat("aaa", "a", 10.0, 1024.0, 0), stat("aaa", "a", 10.0, 1024.0, 10), stat("aaa", "a", 50.0, 1024.0, 40)];
+    assert_eq!(gather(Mode::Avg, stats, Utc::now())[0].cpuPercentage, 34.0);
+
+    let single = gather(Mode::Avg, vec![stat("aaa", "a", 7.0, 1024.0, 0)], Utc::now());
+    assert_eq!(single[0].cpuPercentage, 7.0);
+
+    let equal = vec![stat("aaa", "a", 1.0, 1024.0, 5), stat("aaa", "a", 2.0, 1024.0, 5), stat("aaa", "a", 6.0, 1024.0, 5)];
+    assert_eq!(gather(Mode::Avg, equal, Utc::now())[0].cpuPercentage, 3.0);
+  }
+
+  #[test]
+  fn destination_removes_table_and_name_parameters()
+  {
+    let dest = destination("https::addr=dr:9000;username=admin;name=dr;stats_table=statsDR;agent_table=agentDR;").unwrap();
+    assert_eq!(dest.conf, "https::addr=dr:9000;username=admin;");
+    assert_eq!((dest.name.as_str(), dest.stats_table.as_str(), dest.agent_table.as_str()), ("dr", "statsDR", "agentDR"));
+    assert_eq!((dest.disk_table.as_str(), dest.app_table.as_str()), ("", ""));
+  }
+
+  #[test]
+  fn destination_name_defaults_to_the_address()
+  {
+    let dest = destination("tcp::addr=primary:9009").unwrap();
+    assert_eq!((dest.name.as_str(), dest.conf.as_str()), ("primary:9009", "tcp::addr=primary:9009;"));
+  }
+
+  #[test]
+  fn destination_keeps_escaped_semicolons()
+  {
+    let dest = destination("http::addr=h:9000;password=a;;b;;;name=x;;y;").unwrap();
+    assert_eq!(dest.conf, "http::addr=h:9000;password=a;;b;;;");
+    assert_eq!(dest.name, "x;y");
+  }
+
+  #[test]
+  fn destination_rejects_invalid_strings()
+  {
+    assert!(destination("addr=h:9000;").is_err());
+    assert!(destination("udp::addr=h:9000;").is_err());
+    assert!(destination("tcp::name=x;").is_err());
+    assert!(destination("tcp::addr=h:9009;tls;").is_err());
+  }
+
+  #[test]
+  fn destinations_default_tables_to_the_options()
+  {
+    let cli = super::super::cli(&["--stats-table", "stats", "--destination", "tcp::addr=a:9009;disk_table=disksA;", "--destination", "tcp::addr=b:9009;"]);
+    let dests = destinations(&cli);
+    assert_eq!(dests.len(), 2);
+    assert_eq!((dests[0].stats_table.as_str(), dests[0].disk_table.as_str()), ("stats", "disksA"));
+    assert_eq!((dests[1].stats_table.as_str(), dests[1].disk_table.as_str(), dests[1].agent_table.as_str()), ("stats", "diskStats", "agentStats"));
+
+    let dests = destinations(&super::super::cli(&["--questdb", "qdb", "--port", "9000", "--transport-protocol", "http"]));
+    assert_eq!((dests[0].name.as_str(), dests[0].conf.as_str()), ("qdb:9000", "http::addr=qdb:9000;"));
+  }
+
+  #[test]
+  fn describe_renders_each_value_kind()
+  {
+    let ilp = "things,host=a,name=b s=\"t\",b=t,f=FALSE,l=-5i,ts=1700000000000000t,tn=1700000000000000000n,d=1.5,e=1e10 1\n\
+      things,host=a,kind=c s=\"x\",n=2i 2\n\
+      other b=true 3\n";
+    assert_eq!(describe(ilp), "# table things\n#   symbols: host, name, kind\n\
+      #   columns: s string, b boolean, f boolean, l long, ts timestamp, tn timestamp, d double, e double, n long\n\
+      # table other\n#   symbols: \n#   columns: b boolean\n");
+  }
+
+  #[test]
+  fn counter_rates_use_the_batch_duration()
+  {
+    let cli = super::super::cli(&["--node", "node1"]);
+    let counter = Metric{name: "hits".to_string(), kind: Kind::Counter, tags: String::new(), samples: 3, value: 60.0, summary: None};
+    let mut batch = Batch{time: Utc::now(), stats: Vec::new(), disks: Vec::new(), metrics: vec![counter], agent: AgentStats::default(),
+      coverage: 0.5, duration: 30.0};
+
+    let rows = buffer(&cli, &Destination::new(&cli), &batch).unwrap();
+    assert!(rows.as_str().contains("appStats,host=node1,metric=hits,type=counter samples=3i,value=60.0,rate=2.0 "), "{}", rows.as_str());
+
+    batch.duration = 0.0;
+    assert!(!buffer(&cli, &Destination::new(&cli), &batch).unwrap().as_str().contains("rate="));
+  }
+
+  #[test]
+  fn label_columns_are_sanitised_and_prefixed()
+  {
+    let keys = ["com.docker.compose.service", "name", "Host", "team", "team"].map(String::from);
+    let columns = label_columns(&keys).unwrap();
+    let names : Vec<&str> = columns.iter().map(|(_, c)| c.as_str()).collect();
+    assert_eq!(names, vec!["com_docker_compose_service", "label_name", "label_Host", "team"]);
+    assert_eq!(columns[0].0, "com.docker.compose.service");
+  }
+
+  #[test]
+  fn label_columns_reject_keys_mapping_to_the_same_column()
+  {
+    assert!(label_columns(&["a.b".to_string(), "a-b".to_string()]).is_err());
+  }
+}
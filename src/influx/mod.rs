@@ -0,0 +1,333 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use log::info;
+
+use super::{Cli, Escape, InfluxVersion, Precision};
+use super::agent;
+use super::sink::{Batch, Sink};
+use super::stats::{DiskStats, Stats};
+use super::statsd::Client;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builder for a single InfluxDB line protocol record.
+struct Line
+{
+  escape: Escape,
+  tags: String,
+  fields: String
+}
+
+impl Line
+{
+  fn new(escape: Escape, measurement: &str) -> Line
+  {
+    Line{escape, tags: escape.apply(measurement, &[',', ' ']), fields: String::new()}
+  }
+
+  fn tag(mut self, key: &str, value: &str) -> Line
+  {
+    if value.is_empty() { return self; }
+    self.tags.push_str(&format!(",{}={}", self.escape.apply(key, &[',', '=', ' ']), self.escape.apply(value, &[',', '=', ' '])));
+    self
+  }
+
+  fn field(mut self, key: &str, value: String) -> Line
+  {
+    if !self.fields.is_empty() { self.fields.push(','); }
+    self.fields.push_str(&format!("{}={}", self.escape.apply(key, &[',', '=', ' ']), value));
+    self
+  }
+
+  /// Add a float field.  `NaN` and infinite values are left out as InfluxDB would reject the
+  /// whole write.
+  fn float(self, key: &str, value: f64) -> Line
+  {
+    if !value.is_finite() { return self; }
+    self.field(key, format!("{}", value))
+  }
+
+  fn int(self, key: &str, value: i64) -> Line
+  {
+    self.field(key, format!("{}i", value))
+  }
+
+  fn string(self, key: &str, value: &str) -> Line
+  {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    self.field(key, format!("\"{}\"", value))
+  }
+
+  fn at(self, time: DateTime<Utc>, precision: Precision) -> String
+  {
+    format!("{} {} {}", self.tags, self.fields, precision.timestamp(time))
+  }
+}
+
+impl Escape
+{
+  /// Escape or replace the special characters.  Line breaks cannot be escaped in line protocol,
+  /// so are always replaced with `_`.
+  fn apply(&self, value: &str, special: &[char]) -> String
+  {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars()
+    {
+      if c == '\n' || c == '\r' { result.push('_'); continue; }
+      if special.contains(&c) || c == '\\'
+      {
+        match self
+        {
+          Escape::Strict => { result.push('\\'); result.push(c); },
+          Escape::Sanitise => result.push('_')
+        }
+      }
+      else { result.push(c); }
+    }
+    result
+  }
+}
+
+impl Precision
+{
+  fn timestamp(&self, time: DateTime<Utc>) -> i64
+  {
+    match self
+    {
+      Precision::Ns => time.timestamp_nanos_opt().unwrap_or_default(),
+      Precision::Us => time.timestamp_micros(),
+      Precision::Ms => time.timestamp_millis(),
+      Precision::S => time.timestamp()
+    }
+  }
+
+  fn query(&self, version: InfluxVersion) -> &'static str
+  {
+    match (self, version)
+    {
+      (Precision::Ns, _) => "ns",
+      (Precision::Us, InfluxVersion::V1) => "u",
+      (Precision::Us, InfluxVersion::V2) => "us",
+      (Precision::Ms, _) => "ms",
+      (Precision::S, _) => "s"
+    }
+  }
+}
+
+/// Format the container and disk statistics as line protocol, using the same measurement,
+/// tag and field names as the QuestDB tables.
+pub fn lines(cli: &Cli, stats: &[Stats], disks: &[DiskStats], time: DateTime<Utc>) -> Vec<String>
+{
+  let mut vec : Vec<String> = Vec::with_capacity(stats.len() + disks.len());
+  let escape = cli.influx_escape;
+
+  for stat in stats
+  {
+    vec.push(Line::new(escape, &cli.table).
+        tag("host", &cli.host).
+        tag("container", &stat.container).
+        tag("name", &stat.name).
+        string("id", &stat.id).
+        float("cpu", stat.cpuPercentage).
+        float("memory_percentage", stat.memoryPercentage).
+        int("pids", stat.pids as i64).
+        float("block_io_in", stat.blockIO.incoming.bytes()).
+        float("block_io_out", stat.blockIO.outgoing.bytes()).
+        float("net_io_in", stat.netIO.incoming.bytes()).
+        float("net_io_out", stat.netIO.outgoing.bytes()).
+        float("memory_use", stat.memoryUsage.bytes()).
+        float("total_memory", stat.totalMemory.bytes()).
+        at(stat.time, cli.influx_precision));
+  }
+
+  for disk in disks
+  {
+    vec.push(Line::new(escape, &cli.disk_table).
+        tag("host", &cli.host).
+        tag("name", &disk.name).
+        tag("file_system", &disk.fileSystem).
+        tag("mount_point", &disk.mountPoint).
+        tag("type", &disk.kind).
+        int("available_space", disk.availableSpace as i64).
+        float("percentage_use", disk.percentage()).
+        int("read_bytes", disk.readBytes as i64).
+        int("write_bytes", disk.writtenBytes as i64).
+        at(time, cli.influx_precision));
+  }
+
+  vec
+}
+
+fn http(cli: &Cli, url: &str, body: String) -> Result<(), String>
+{
+  let base = url.trim_end_matches('/');
+  let precision = cli.influx_precision.query(cli.influx_version);
+  let agent = ureq::Agent::config_builder().timeout_global(Some(TIMEOUT)).build().new_agent();
+
+  let mut req = match cli.influx_version
+  {
+    InfluxVersion::V1 =>
+    {
+      let mut req = agent.post(format!("{}/write", base)).query("db", &cli.influx_database).query("precision", precision);
+      if let Some(username) = &cli.influx_username { req = req.query("u", username); }
+      if let Some(password) = &cli.influx_password { req = req.query("p", password); }
+      req
+    },
+    InfluxVersion::V2 =>
+    {
+      let mut req = agent.post(format!("{}/api/v2/write", base)).query("bucket", &cli.influx_bucket).query("precision", precision);
+      if let Some(org) = &cli.influx_org { req = req.query("org", org); }
+      req
+    }
+  };
+
+  if let Some(token) = &cli.influx_token { req = req.header("Authorization", format!("Token {}", token)); }
+  let bytes = body.len();
+  req.header("Content-Type", "text/plain; charset=utf-8").send(body).map_err(|e| format!("InfluxDB write to {} failed. {}", base, e))?;
+  agent::sent(bytes);
+  Ok(())
+}
+
+pub fn publish(cli: &Cli, stats: &[Stats], disks: &[DiskStats], time: DateTime<Utc>) -> Result<(), String>
+{
+  let url = match &cli.influx_url { Some(url) => url, None => return Ok(()) };
+
+  let lines = lines(cli, stats, disks, time);
+  if url.starts_with("udp://")
+  {
+    let client = Client::connect(url).map_err(|e| format!("Failed to connect to InfluxDB UDP listener {}. {}", url, e))?;
+    client.send(&lines).map_err(|e| format!("InfluxDB write to {} failed. {}", url, e))?;
+  }
+  else { http(cli, url, lines.join("\n"))?; }
+
+  info!("Published {:?} container statistics for {} to {}.", stats.len(), cli.host, url);
+  Ok(())
+}
+
+/// Writes line protocol to an InfluxDB compatible endpoint.
+pub struct Influx
+{
+  cli: Cli
+}
+
+impl Influx
+{
+  pub fn new(cli: &Cli) -> Influx
+  {
+    Influx{cli: cli.clone()}
+  }
+}
+
+impl Sink for Influx
+{
+  fn name(&self) -> String
+  {
+    format!("InfluxDB {}", self.cli.influx_url.as_deref().unwrap_or_default())
+  }
+
+  fn publish(&mut self, batch: &Batch) -> Result<(), String>
+  {
+    publish(&self.cli, &batch.stats, &batch.disks, batch.time)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::thread;
+  use chrono::TimeDelta;
+  use tiny_http::{Response, Server};
+
+  use super::*;
+  use super::super::cli;
+
+  fn stats() -> Vec<Stats>
+  {
+    let mut stat = Stats::new();
+    stat.id = "3f2a1b".to_string();
+    stat.container = "3f2a1b".to_string();
+    stat.name = "web 1,a=b".to_string();
+    stat.cpuPercentage = 1.5;
+    stat.pids = 12;
+    stat.time = DateTime::UNIX_EPOCH + TimeDelta::milliseconds(1500);
+    vec![stat]
+  }
+
+  fn disk() -> DiskStats
+  {
+    DiskStats{name: "sda".to_string(), fileSystem: "ext4".to_string(), mountPoint: "/".to_string(), kind: "SSD".to_string(),
+      availableSpace: 0, totalSpace: 0, readBytes: 1, writtenBytes: 2}
+  }
+
+  #[test]
+  fn escape_handles_special_characters_and_line_breaks()
+  {
+    assert_eq!(Escape::Strict.apply("a b,c=d\\e", &[',', '=', ' ']), "a\\ b\\,c\\=d\\\\e");
+    assert_eq!(Escape::Sanitise.apply("a b,c=d\\e", &[',', '=', ' ']), "a_b_c_d_e");
+    assert_eq!(Escape::Strict.apply("web\nnext\r", &[',', ' ']), "web_next_");
+
+    let cli = cli(&["--node", "node 1", "--influx-precision", "s"]);
+    let lines = lines(&cli, &stats(), &[], Utc::now());
+    assert!(lines[0].starts_with("containerStats,host=node\\ 1,container=3f2a1b,name=web\\ 1\\,a\\=b id=\"3f2a1b\",cpu=1.5,"), "{}", lines[0]);
+  }
+
+  #[test]
+  fn timestamps_use_the_precision()
+  {
+    let time = DateTime::UNIX_EPOCH + TimeDelta::milliseconds(1500);
+    let precisions = [("ns", "1500000000"), ("us", "1500000"), ("ms", "1500"), ("s", "1")];
+    for (precision, timestamp) in precisions
+    {
+      let lines = lines(&cli(&["--influx-precision", precision]), &stats(), &[], time);
+      assert!(lines[0].ends_with(&format!("pids=12i,block_io_in=0,block_io_out=0,net_io_in=0,net_io_out=0,memory_use=0,total_memory=0 {}", timestamp)),
+        "{}", lines[0]);
+    }
+  }
+
+  #[test]
+  fn fields_that_are_not_finite_are_left_out()
+  {
+    let lines = lines(&cli(&["--node", "node1", "--influx-precision", "s"]), &[], &[disk()], DateTime::UNIX_EPOCH);
+    assert_eq!(lines[0], "diskStats,host=node1,name=sda,file_system=ext4,mount_point=/,type=SSD available_space=0i,read_bytes=1i,write_bytes=2i 0");
+  }
+
+  /// Publish to a stub receiver, returning the request URL, authorization header and body.
+  fn receive(args: &[&str]) -> (String, Option<String>, String)
+  {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", server.server_addr().to_ip().unwrap());
+    let receiver = thread::spawn(move ||
+        {
+          let mut request = server.recv().unwrap();
+          let authorization = request.headers().iter().find(|h| h.field.equiv("Authorization")).map(|h| h.value.to_string());
+          let received = (request.url().to_string(), authorization);
+          let mut body = String::new();
+          request.as_reader().read_to_string(&mut body).unwrap();
+          request.respond(Response::empty(204)).unwrap();
+          (received, body)
+        });
+
+    let args : Vec<&str> = ["--influx-url", &url].iter().copied().chain(args.iter().copied()).collect();
+    publish(&cli(&args), &stats(), &[], Utc::now()).unwrap();
+    let ((url, authorization), body) = receiver.join().unwrap();
+    (url, authorization, body)
+  }
+
+  #[test]
+  fn v1_writes_with_database_and_credentials()
+  {
+    let (url, authorization, body) = receive(&["--influx-version", "v1", "--influx-database", "db", "--influx-username", "u1",
+      "--influx-password", "p1", "--influx-precision", "us"]);
+    assert_eq!(url, "/write?db=db&precision=u&u=u1&p=p1");
+    assert_eq!(authorization, None);
+    assert!(body.starts_with("containerStats,"));
+  }
+
+  #[test]
+  fn v2_writes_with_org_bucket_and_token()
+  {
+    let (url, authorization, _) = receive(&["--influx-org", "org", "--influx-bucket", "bucket", "--influx-token", "secret"]);
+    assert_eq!(url, "/api/v2/write?bucket=bucket&precision=ns&org=org");
+    assert_eq!(authorization.as_deref(), Some("Token secret"));
+  }
+}
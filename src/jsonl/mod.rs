@@ -0,0 +1,274 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use super::{Cli, Rotation};
+use super::sink::{Batch, Sink};
+use super::stats::{DiskStats, Stats};
+
+/// Version of the record schema.  Incremented when fields are renamed or removed.
+const SCHEMA: u32 = 1;
+
+/// A container record.  Field names carry their unit.
+#[derive(Serialize)]
+pub struct ContainerRecord<'a>
+{
+  r#type: &'static str,
+  schema: u32,
+  time: DateTime<Utc>,
+  host: &'a str,
+  container: &'a str,
+  name: &'a str,
+  id: &'a str,
+  cpu_percent: f64,
+  memory_percent: f64,
+  memory_use_bytes: f64,
+  total_memory_bytes: f64,
+  block_io_in_bytes: f64,
+  block_io_out_bytes: f64,
+  net_io_in_bytes: f64,
+  net_io_out_bytes: f64,
+  pids: u32,
+  /// The share of the interval covered, only for partial intervals.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  coverage: Option<f64>
+}
+
+impl<'a> ContainerRecord<'a>
+{
+  pub fn new(host: &'a str, stat: &'a Stats) -> ContainerRecord<'a>
+  {
+    ContainerRecord{r#type: "container", schema: SCHEMA, time: stat.time, host, container: &stat.container, name: &stat.name, id: &stat.id,
+      cpu_percent: stat.cpuPercentage, memory_percent: stat.memoryPercentage,
+      memory_use_bytes: stat.memoryUsage.bytes(), total_memory_bytes: stat.totalMemory.bytes(),
+      block_io_in_bytes: stat.blockIO.incoming.bytes(), block_io_out_bytes: stat.blockIO.outgoing.bytes(),
+      net_io_in_bytes: stat.netIO.incoming.bytes(), net_io_out_bytes: stat.netIO.outgoing.bytes(), pids: stat.pids, coverage: None}
+  }
+}
+
+/// A disk record.  Field names carry their unit.
+#[derive(Serialize)]
+pub struct DiskRecord<'a>
+{
+  r#type: &'static str,
+  schema: u32,
+  time: DateTime<Utc>,
+  host: &'a str,
+  name: &'a str,
+  file_system: &'a str,
+  mount_point: &'a str,
+  kind: &'a str,
+  available_space_bytes: u64,
+  total_space_bytes: u64,
+  available_percent: f64,
+  read_bytes: u64,
+  write_bytes: u64
+}
+
+impl<'a> DiskRecord<'a>
+{
+  pub fn new(host: &'a str, disk: &'a DiskStats, time: DateTime<Utc>) -> DiskRecord<'a>
+  {
+    DiskRecord{r#type: "disk", schema: SCHEMA, time, host, name: &disk.name, file_system: &disk.fileSystem,
+      mount_point: &disk.mountPoint, kind: &disk.kind, available_space_bytes: disk.availableSpace,
+      total_space_bytes: disk.totalSpace, available_percent: disk.percentage(),
+      read_bytes: disk.readBytes, write_bytes: disk.writtenBytes}
+  }
+}
+
+/// Header written at the start of each file (or stream) describing the record fields.
+fn header() -> serde_json::Value
+{
+  json!({
+    "type": "schema",
+    "schema": SCHEMA,
+    "records": {
+      "container": {
+        "time": "RFC 3339 UTC timestamp of the interval, or of the sample in raw mode",
+        "host": "string",
+        "container": "string",
+        "name": "string",
+        "id": "string",
+        "cpu_percent": "percent of a single CPU, may exceed 100",
+        "memory_percent": "percent of the memory limit",
+        "memory_use_bytes": "bytes",
+        "total_memory_bytes": "bytes",
+        "block_io_in_bytes": "bytes, cumulative",
+        "block_io_out_bytes": "bytes, cumulative",
+        "net_io_in_bytes": "bytes, cumulative",
+        "net_io_out_bytes": "bytes, cumulative",
+        "pids": "count"
+      },
+      "disk": {
+        "time": "RFC 3339 UTC timestamp of the interval",
+        "host": "string",
+        "name": "string",
+        "file_system": "string",
+        "mount_point": "string",
+        "kind": "string",
+        "available_space_bytes": "bytes",
+        "total_space_bytes": "bytes",
+        "available_percent": "percent of the total space",
+        "read_bytes": "bytes",
+        "write_bytes": "bytes"
+      }
+    }
+  })
+}
+
+/// A file that is rotated by size or time.  Rotated files are renamed with a timestamp suffix,
+/// and the oldest removed when there are more than the configured number.
+struct RotatingFile
+{
+  path: PathBuf,
+  writer: BufWriter<File>,
+  size: u64,
+  opened: DateTime<Local>
+}
+
+impl RotatingFile
+{
+  fn open(path: &Path) -> io::Result<RotatingFile>
+  {
+    if let Some(parent) = path.parent() && !parent.as_os_str().is_empty() { fs::create_dir_all(parent)?; }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let opened = metadata.modified().map(DateTime::<Local>::from).unwrap_or_else(|_| Local::now());
+    let mut rf = RotatingFile{path: path.to_path_buf(), writer: BufWriter::new(file), size: metadata.len(), opened};
+    if rf.size == 0 { rf.write_line(&header().to_string())?; }
+    Ok(rf)
+  }
+
+  fn due(&self, cli: &Cli, now: DateTime<Local>) -> bool
+  {
+    if let Some(max) = cli.json_max_size && self.size >= max * 1024 * 1024 { return true; }
+    match cli.json_rotation
+    {
+      Rotation::Never => false,
+      Rotation::Hourly => now.format("%Y%m%d%H").to_string() != self.opened.format("%Y%m%d%H").to_string(),
+      Rotation::Daily => now.date_naive() != self.opened.date_naive()
+    }
+  }
+
+  fn rotate(&mut self, retain: usize) -> io::Result<()>
+  {
+    self.writer.flush()?;
+    let name = self.path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let rotated = self.path.with_file_name(format!("{}.{}", name, Local::now().format("%Y%m%dT%H%M%S%.3f")));
+    fs::rename(&self.path, &rotated)?;
+    info!("Rotated {} to {}", self.path.display(), rotated.display());
+
+    let dir = match self.path.parent() { Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(), _ => PathBuf::from(".") };
+    let prefix = format!("{}.", name);
+    let mut files : Vec<PathBuf> = fs::read_dir(&dir)?.filter_map(|e| e.ok()).map(|e| e.path()).
+        filter(|p| p.file_name().map(|n| n.to_string_lossy().starts_with(&prefix)).unwrap_or(false)).collect();
+    files.sort();
+    while files.len() > retain
+    {
+      let file = files.remove(0);
+      if let Err(e) = fs::remove_file(&file) { warn!("Failed to remove {}. {}", file.display(), e); }
+    }
+
+    let path = self.path.clone();
+    *self = RotatingFile::open(&path)?;
+    Ok(())
+  }
+
+  fn write_line(&mut self, line: &str) -> io::Result<()>
+  {
+    self.writer.write_all(line.as_bytes())?;
+    self.writer.write_all(b"\n")?;
+    self.size += line.len() as u64 + 1;
+    Ok(())
+  }
+}
+
+enum Output
+{
+  Stdout,
+  File(RotatingFile)
+}
+
+/// Writes each container and disk record as a JSON object per line, to stdout or a rotating file.
+pub struct JsonLines
+{
+  cli: Cli,
+  output: Option<Output>
+}
+
+impl JsonLines
+{
+  pub fn new(cli: &Cli) -> JsonLines
+  {
+    JsonLines{cli: cli.clone(), output: None}
+  }
+
+  fn lines(&self, batch: &Batch) -> Vec<String>
+  {
+    let mut vec : Vec<String> = Vec::with_capacity(batch.stats.len() + batch.disks.len());
+    for stat in &batch.stats
+    {
+      let mut record = ContainerRecord::new(&self.cli.host, stat);
+      record.coverage = batch.partial();
+      vec.push(serde_json::to_string(&record).unwrap_or_default());
+    }
+    for disk in &batch.disks
+    {
+      vec.push(serde_json::to_string(&DiskRecord::new(&self.cli.host, disk, batch.time)).unwrap_or_default());
+    }
+    vec
+  }
+
+  fn write(&mut self, lines: &[String]) -> io::Result<()>
+  {
+    let path = self.cli.json.clone().unwrap_or_default();
+    if self.output.is_none()
+    {
+      self.output = Some(if path == "-"
+        {
+          println!("{}", header());
+          Output::Stdout
+        }
+        else { Output::File(RotatingFile::open(Path::new(&path))?) });
+    }
+
+    match self.output.as_mut().unwrap()
+    {
+      Output::Stdout =>
+      {
+        let mut stdout = io::stdout().lock();
+        for line in lines { writeln!(stdout, "{}", line)?; }
+        stdout.flush()
+      },
+      Output::File(file) =>
+      {
+        if file.due(&self.cli, Local::now()) { file.rotate(self.cli.json_retain)?; }
+        for line in lines { file.write_line(line)?; }
+        file.writer.flush()
+      }
+    }
+  }
+}
+
+impl Sink for JsonLines
+{
+  fn name(&self) -> String
+  {
+    format!("JSON lines {}", self.cli.json.as_deref().unwrap_or_default())
+  }
+
+  fn publish(&mut self, batch: &Batch) -> Result<(), String>
+  {
+    let lines = self.lines(batch);
+    self.write(&lines).map_err(|e|
+        {
+          self.output = None;
+          e.to_string()
+        })
+  }
+}
@@ -0,0 +1,384 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Result};
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use float_ord::sort;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Kind
+{
+  Counter,
+  Gauge,
+  Timer,
+  Histogram,
+  Distribution,
+  Set
+}
+
+impl Kind
+{
+  fn parse(value: &str) -> Option<Kind>
+  {
+    match value
+    {
+      "c" => Some(Kind::Counter),
+      "g" => Some(Kind::Gauge),
+      "ms" => Some(Kind::Timer),
+      "h" => Some(Kind::Histogram),
+      "d" => Some(Kind::Distribution),
+      "s" => Some(Kind::Set),
+      _ => None
+    }
+  }
+
+  pub fn name(&self) -> &'static str
+  {
+    match self
+    {
+      Kind::Counter => "counter",
+      Kind::Gauge => "gauge",
+      Kind::Timer => "timer",
+      Kind::Histogram => "histogram",
+      Kind::Distribution => "distribution",
+      Kind::Set => "set"
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key
+{
+  name: String,
+  kind: Kind,
+  tags: String
+}
+
+#[derive(Debug)]
+enum Value
+{
+  Counter(f64),
+  Gauge(f64),
+  Samples{values: Vec<f64>, count: f64},
+  Set(HashSet<String>)
+}
+
+/// Summary of a timer, histogram or distribution over an interval.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Summary
+{
+  pub count: f64,
+  pub sum: f64,
+  pub min: f64,
+  pub max: f64,
+  pub mean: f64,
+  pub median: f64,
+  pub p90: f64,
+  pub p95: f64,
+  pub p99: f64
+}
+
+/// An application metric aggregated over an interval.  Counters and gauges carry their
+/// value, sets the number of unique members, and sampled types a summary.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Metric
+{
+  pub name: String,
+  pub kind: Kind,
+  pub tags: String,
+  pub samples: u64,
+  pub value: f64,
+  pub summary: Option<Summary>
+}
+
+/// Accumulates StatsD metrics received between publishing intervals.
+#[derive(Debug, Default)]
+pub struct Aggregator
+{
+  metrics: HashMap<Key, (Value, u64)>,
+  parsed: u64,
+  rejected: u64
+}
+
+impl Aggregator
+{
+  pub fn new() -> Aggregator
+  {
+    Aggregator::default()
+  }
+
+  /// Parse a datagram, which may hold several newline separated metrics.
+  pub fn add(&mut self, datagram: &str)
+  {
+    for line in datagram.lines()
+    {
+      let line = line.trim();
+      if line.is_empty() { continue; }
+      if self.add_line(line) { self.parsed += 1; }
+      else
+      {
+        self.rejected += 1;
+        debug!("Rejected StatsD metric {}", line);
+      }
+    }
+  }
+
+  /// Parse a single `name:value[:value...]|type[|@rate][|#tag,...]` metric.
+  fn add_line(&mut self, line: &str) -> bool
+  {
+    let mut sections = line.split('|');
+    let (name, values) = match sections.next().and_then(|s| s.split_once(':')) { Some(v) => v, None => return false };
+    let kind = match sections.next().and_then(Kind::parse) { Some(k) => k, None => return false };
+    if name.is_empty() { return false; }
+
+    let mut rate = 1.0;
+    let mut tags : Vec<&str> = Vec::new();
+    for section in sections
+    {
+      if let Some(r) = section.strip_prefix('@')
+      {
+        rate = match r.parse::<f64>() { Ok(r) if r > 0.0 && r <= 1.0 => r, _ => return false };
+      }
+      else if let Some(t) = section.strip_prefix('#')
+      {
+        tags.extend(t.split(',').filter(|t| !t.is_empty()));
+      }
+    }
+    tags.sort_unstable();
+    tags.dedup();
+
+    // A line with an invalid value is rejected as a whole, before any value is applied.
+    if kind != Kind::Set && !values.split(':').all(|v| v.parse::<f64>().is_ok_and(|v| v.is_finite())) { return false; }
+
+    let key = Key{name: name.to_string(), kind, tags: tags.join(",")};
+    for value in values.split(':')
+    {
+      if !self.add_value(&key, value, rate) { return false; }
+    }
+    true
+  }
+
+  fn add_value(&mut self, key: &Key, value: &str, rate: f64) -> bool
+  {
+    if key.kind == Kind::Set
+    {
+      let entry = self.metrics.entry(key.clone()).or_insert_with(|| (Value::Set(HashSet::new()), 0));
+      if let Value::Set(members) = &mut entry.0 { members.insert(value.to_string()); }
+      entry.1 += 1;
+      return true;
+    }
+
+    let number = match value.parse::<f64>() { Ok(v) if v.is_finite() => v, _ => return false };
+    let delta = key.kind == Kind::Gauge && (value.starts_with('+') || value.starts_with('-'));
+    let entry = self.metrics.entry(key.clone()).or_insert_with(|| match key.kind
+    {
+      Kind::Counter => (Value::Counter(0.0), 0),
+      Kind::Gauge => (Value::Gauge(0.0), 0),
+      _ => (Value::Samples{values: Vec::new(), count: 0.0}, 0)
+    });
+
+    match &mut entry.0
+    {
+      Value::Counter(sum) => *sum += number / rate,
+      Value::Gauge(current) => { if delta { *current += number; } else { *current = number; } },
+      Value::Samples{values, count} =>
+      {
+        values.push(number);
+        *count += 1.0 / rate;
+      },
+      Value::Set(_) => return false
+    }
+    entry.1 += 1;
+    true
+  }
+
+  /// Metrics parsed and rejected since the aggregator was created.
+  pub fn counts(&self) -> (u64, u64)
+  {
+    (self.parsed, self.rejected)
+  }
+
+  /// Remove and return the metrics aggregated since the last call.  Gauges retain their
+  /// last value so that subsequent deltas apply to it, as StatsD servers do.
+  pub fn take(&mut self) -> Vec<Metric>
+  {
+    let mut vec : Vec<Metric> = Vec::with_capacity(self.metrics.len());
+    let mut gauges : HashMap<Key, (Value, u64)> = HashMap::new();
+
+    for (key, (value, samples)) in self.metrics.drain()
+    {
+      let mut metric = Metric{name: key.name.clone(), kind: key.kind, tags: key.tags.clone(), samples, value: 0.0, summary: None};
+      match value
+      {
+        Value::Counter(sum) => metric.value = sum,
+        Value::Gauge(current) =>
+        {
+          metric.value = current;
+          gauges.insert(key, (Value::Gauge(current), 0));
+        },
+        Value::Samples{mut values, count} =>
+        {
+          metric.summary = Some(summarise(&mut values, count));
+          metric.value = metric.summary.as_ref().unwrap().mean;
+        },
+        Value::Set(members) => metric.value = members.len() as f64
+      }
+
+      if samples > 0 { vec.push(metric); }
+    }
+
+    self.metrics = gauges;
+    vec
+  }
+}
+
+fn summarise(values: &mut [f64], count: f64) -> Summary
+{
+  if values.is_empty() { return Summary::default(); }
+
+  sort(values);
+  let percentile = |p: f64| -> f64
+  {
+    let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+  };
+
+  let sum = values.iter().sum::<f64>();
+  Summary{count, sum, min: values[0], max: values[values.len() - 1], mean: sum / values.len() as f64,
+    median: percentile(50.0), p90: percentile(90.0), p95: percentile(95.0), p99: percentile(99.0)}
+}
+
+/// Bind the StatsD UDP port and feed received datagrams to the aggregator until terminated.
+pub fn listen(address: &str, aggregator: Arc<Mutex<Aggregator>>, term: Arc<AtomicBool>) -> Result<JoinHandle<()>>
+{
+  let socket = UdpSocket::bind(address)?;
+  socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+  info!("Listening for StatsD metrics on {}", address);
+
+  Ok(thread::spawn(move ||
+      {
+        let mut buf = [0u8; 65535];
+        while !term.load(Ordering::Relaxed)
+        {
+          match socket.recv(&mut buf)
+          {
+            Ok(size) => aggregator.lock().unwrap_or_else(|e| e.into_inner()).add(&String::from_utf8_lossy(&buf[..size])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => warn!("Error receiving StatsD metrics. {}", e)
+          }
+        }
+      }))
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn find<'a>(metrics: &'a [Metric], name: &str, tags: &str) -> &'a Metric
+  {
+    metrics.iter().find(|m| m.name == name && m.tags == tags).unwrap_or_else(|| panic!("No metric {} {}", name, tags))
+  }
+
+  #[test]
+  fn counters_are_scaled_by_sample_rate()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("hits:2|c|@0.5\nhits:1|c");
+    let metrics = agg.take();
+    let hits = find(&metrics, "hits", "");
+    assert_eq!((hits.kind, hits.value, hits.samples), (Kind::Counter, 5.0, 2));
+  }
+
+  #[test]
+  fn lines_may_hold_several_values()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("latency:10:20:30|ms");
+    let metrics = agg.take();
+    let summary = find(&metrics, "latency", "").summary.as_ref().unwrap();
+    assert_eq!((summary.count, summary.sum, summary.min, summary.max, summary.mean), (3.0, 60.0, 10.0, 30.0, 20.0));
+    assert_eq!(agg.counts(), (1, 0));
+  }
+
+  #[test]
+  fn sampled_counts_are_scaled_by_sample_rate()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("latency:5|ms|@0.1");
+    let metrics = agg.take();
+    let latency = find(&metrics, "latency", "");
+    assert_eq!((latency.samples, latency.summary.as_ref().unwrap().count), (1, 10.0));
+  }
+
+  #[test]
+  fn gauge_deltas_apply_to_the_last_value()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("queue:10|g\nqueue:+5|g\nqueue:-3|g");
+    assert_eq!(find(&agg.take(), "queue", "").value, 12.0);
+
+    // The value is kept across intervals, but only reported when updated.
+    assert!(agg.take().is_empty());
+    agg.add("queue:+1|g");
+    assert_eq!(find(&agg.take(), "queue", "").value, 13.0);
+  }
+
+  #[test]
+  fn tags_identify_the_series_in_any_order()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("requests:1|c|#env:prod,app:web\nrequests:1|c|#app:web,env:prod\nrequests:1|c|@1|#app:web,app:web");
+    agg.add("requests:4|c");
+    let metrics = agg.take();
+    assert_eq!(metrics.len(), 3);
+    assert_eq!(find(&metrics, "requests", "app:web,env:prod").value, 2.0);
+    assert_eq!(find(&metrics, "requests", "app:web").value, 1.0);
+    assert_eq!(find(&metrics, "requests", "").value, 4.0);
+  }
+
+  #[test]
+  fn sets_count_unique_members()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("users:alice|s\nusers:bob|s\nusers:alice|s");
+    let metrics = agg.take();
+    let users = find(&metrics, "users", "");
+    assert_eq!((users.value, users.samples), (2.0, 3));
+  }
+
+  #[test]
+  fn summaries_use_nearest_rank_percentiles()
+  {
+    let mut agg = Aggregator::new();
+    let values : Vec<String> = (1..=100).rev().map(|v| v.to_string()).collect();
+    agg.add(&format!("size:{}|d", values.join(":")));
+    let metrics = agg.take();
+    let summary = find(&metrics, "size", "").summary.as_ref().unwrap();
+    assert_eq!((summary.median, summary.p90, summary.p95, summary.p99), (50.0, 90.0, 95.0, 99.0));
+    assert_eq!((summary.min, summary.max, summary.mean), (1.0, 100.0, 50.5));
+  }
+
+  #[test]
+  fn invalid_lines_are_rejected()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("nocolon|c\nx:1|zz\nx:abc|c\nx:1|c|@2\nx:1|c|@0\n:1|c\nx:nan|g\nx:1\n\nok:1|c");
+    assert_eq!(agg.counts(), (1, 8));
+    assert_eq!(agg.take().len(), 1);
+  }
+
+  #[test]
+  fn lines_with_an_invalid_value_are_rejected_whole()
+  {
+    let mut agg = Aggregator::new();
+    agg.add("latency:10:abc:30|ms
+hits:1:inf|c");
+    assert_eq!(agg.counts(), (0, 2));
+    assert!(agg.take().is_empty());
+  }
+}
@@ -1,33 +1,71 @@
+mod agent;
+mod archive;
+mod collector;
+mod config;
+mod error;
+mod filter;
+mod graphite;
+mod health;
 mod ilp;
+mod influx;
+mod jsonl;
+mod listener;
+mod otlp;
+mod publisher;
+mod sink;
+mod snapshot;
 mod stats;
+mod statsd;
+mod systemd;
+mod top;
 
-use std::io::{BufRead, BufReader, Error};
-use std::process::Command;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::process::ExitCode;
 use std::thread;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
-use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use chrono::{DateTime, DurationRound, TimeDelta, Utc};
-use clap::Parser;
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use clap_num::number_range;
-use log::{info, debug};
-use serde::{Deserialize};
+use log::{info, debug, warn};
+use serde::{Deserialize, Serialize};
+use sysinfo::System;
 
-#[cfg(target_os = "linux")]
-extern crate libsystemd;
-#[cfg(target_os = "linux")]
-use libsystemd::daemon::{self, NotifyState};
+use error::Error;
+use filter::{Filter, filter};
+use ilp::{Destination, destination, gather};
+use listener::{Aggregator, Metric};
+use publisher::Publisher;
+use sink::{Batch, Sinks};
+use systemd::Systemd;
+use stats::{Stats, disks};
 
-use ilp::{gather, publish};
-use stats::{RawStats, Stats, createStats};
+fn hostname() -> String
+{
+  System::host_name().unwrap_or_else(|| "localhost".to_string())
+}
 
 fn valid_interval(s: &str) -> Result<u8, String> {
   number_range(s, 1, 15)
 }
 
+fn valid_period(s: &str) -> Result<u16, String> {
+  number_range(s, 1, 300)
+}
+
+fn valid_flush(s: &str) -> Result<u16, String> {
+  number_range(s, 1, 3600)
+}
+
+fn valid_depth(s: &str) -> Result<usize, String> {
+  number_range(s, 1, 1024)
+}
+
 #[derive(
-  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, Serialize, PartialEq, Eq, Copy
 )]
 #[serde(rename_all = "lowercase")]
 enum Mode {
@@ -35,7 +73,9 @@ enum Mode {
   #[default]
   Avg,
   /// Use the maximum value from aggregated statistics when publishing to QuestDB
-  Max
+  Max,
+  /// Publish every sample with its own collection timestamp, without aggregating
+  Raw
 }
 
 #[derive(
@@ -53,6 +93,152 @@ enum Protocol {
   https
 }
 
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum Frequency
+{
+  /// Send the aggregated statistics once per interval
+  #[default]
+  Interval,
+  /// Send every sample as soon as it is collected
+  Sample
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum OtlpProtocol
+{
+  /// OTLP over HTTP with protobuf encoded payloads
+  #[default]
+  Http,
+  /// OTLP over gRPC
+  Grpc
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum InfluxVersion
+{
+  /// InfluxDB 1.x `/write` API, also supported by VictoriaMetrics
+  V1,
+  /// InfluxDB 2.x `/api/v2/write` API with org, bucket and token
+  #[default]
+  V2
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum Precision
+{
+  /// Nanoseconds
+  #[default]
+  Ns,
+  /// Microseconds
+  Us,
+  /// Milliseconds
+  Ms,
+  /// Seconds
+  S
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum Escape
+{
+  /// Escape special characters with a backslash as per the line protocol specification
+  #[default]
+  Strict,
+  /// Replace special characters with an underscore
+  Sanitise
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum Rotation
+{
+  /// Do not rotate based on time
+  #[default]
+  Never,
+  /// Rotate at the start of each hour
+  Hourly,
+  /// Rotate at the start of each day
+  Daily
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum ArchiveFormat
+{
+  /// Write a Parquet file per interval
+  #[default]
+  Parquet,
+  /// Append to a daily CSV file
+  Csv
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "kebab-case")]
+enum Overflow
+{
+  /// Drop the oldest queued batch
+  #[default]
+  DropOldest,
+  /// Wait for the publisher, pausing collection
+  Block,
+  /// Write the batch to the spool directory, to be published once the queue has drained
+  Spool
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum Format
+{
+  /// Aligned columns similar to `docker stats`
+  #[default]
+  Table,
+  /// A JSON document with the same record fields as the JSON lines output
+  Json
+}
+
+#[derive(
+  clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
+)]
+#[serde(rename_all = "lowercase")]
+enum Column
+{
+  /// Container name
+  Name,
+  /// CPU percentage
+  #[default]
+  Cpu,
+  /// Memory usage
+  Memory,
+  /// Combined network in and out rate
+  Net,
+  /// Combined block IO in and out rate
+  Block,
+  /// Number of processes or threads
+  Pids
+}
+
 #[cfg(target_os = "linux")]
 #[derive(
   clap::ValueEnum, Clone, Default, Debug, Deserialize, PartialEq, Eq, Copy
@@ -66,17 +252,85 @@ enum Watchdog {
   Disabled
 }
 
-#[derive(Parser, Debug, Clone)]
-#[command(version, about, long_about = None, ignore_errors(true))]
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct App
+{
+  #[command(subcommand)]
+  command: Commands
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands
+{
+  /// Run as a daemon, continually collecting statistics and publishing them periodically.
+  #[command(ignore_errors(true))]
+  Run(Box<Cli>),
+  /// Collect a single sample, or samples for a duration, print the aggregated statistics and exit.
+  Snapshot(Snapshot),
+  /// Display a continuously updating table of container statistics.
+  Top(Top)
+}
+
+#[derive(Args, Debug, Clone)]
+struct Snapshot
+{
+  /// Optional list of disk/block device names for which disk usage statistics are to be displayed.
+  #[clap(short = 'b', long = "block-device")]
+  disks: Vec<String>,
+  /// The host name to display.  Defaults to the system host name.
+  #[arg(short = 'n', long = "node", default_value_t = hostname())]
+  host: String,
+  /// The mode to use when aggregating samples.
+  #[arg(short, long, default_value_t, value_enum)]
+  mode: Mode,
+  /// The number of seconds to collect samples for.  A single sample is taken when not specified.
+  #[arg(short, long)]
+  duration: Option<u64>,
+  /// How often `docker stats` is sampled during the duration, in seconds.  Must be between 1 and 300.
+  #[arg(long = "sample-period", default_value_t = 5, value_parser=valid_period)]
+  sample_period: u16,
+  /// The output format.
+  #[arg(short, long, default_value_t, value_enum)]
+  format: Format
+}
+
+#[derive(Args, Debug, Clone)]
+struct Top
+{
+  /// Optional list of disk/block device names for which disk usage statistics are to be displayed.
+  #[clap(short = 'b', long = "block-device")]
+  disks: Vec<String>,
+  /// The host name to display.  Defaults to the system host name.
+  #[arg(short = 'n', long = "node", default_value_t = hostname())]
+  host: String,
+  /// The mode to use when aggregating samples over the interval.
+  #[arg(short, long, default_value_t, value_enum)]
+  mode: Mode,
+  /// The interval in minutes over which samples are aggregated.
+  #[arg(short, long, value_parser=valid_interval, default_value_t = 1)]
+  interval: u8,
+  /// How often `docker stats` is sampled, in seconds.  Must be between 1 and 300.
+  #[arg(long = "sample-period", default_value_t = 2, value_parser=valid_period)]
+  sample_period: u16,
+  /// The column to sort by initially.
+  #[arg(short, long, default_value_t, value_enum)]
+  sort: Column
+}
+
+#[derive(Args, Debug, Clone)]
 struct Cli {
+  /// Optional TOML or YAML (`.yaml` or `.yml` extension) configuration file.  Options specified on the command line override the file.
+  #[arg(short, long)]
+  config: Option<String>,
   /// Optional list of disk/block device names for which disk usage statistics are to be captured.
   #[clap(short = 'b', long = "block-device")]
   disks: Vec<String>,
   /// The series name to publish disk information to.  Defaults to diskStats.
   #[arg(short, long, default_value = "diskStats")]
   disk_table: String,
-  /// The host name to add to the published data.  Generally the name of the host docker daemon is running on.
-  #[arg(short = 'n', long = "node")]
+  /// The host name to add to the published data.  Generally the name of the host docker daemon is running on.  Defaults to the system host name.
+  #[arg(short = 'n', long = "node", default_value_t = hostname())]
   host: String,
   /// The QuestDB host to publish to.
   #[arg(short, long, default_value = "localhost")]
@@ -90,6 +344,16 @@ struct Cli {
   /// The port on which the QuestDB ILP service is listening.
   #[arg(short, long, default_value_t = 9009)]
   port: u16,
+  /// QuestDB destinations to publish to, as client configuration strings, e.g. `https::addr=dr:9000;username=admin;password=quest;stats_table=containerStats;`.
+  /// May be repeated.  Overrides the questdb, port and transport-protocol options when specified.
+  #[arg(long = "destination", value_parser = destination)]
+  destinations: Vec<Destination>,
+  /// Do not publish to QuestDB.  Use when only other outputs are configured.
+  #[arg(long = "no-questdb")]
+  no_questdb: bool,
+  /// Print the ILP payload for each QuestDB destination to stdout instead of publishing.  Nothing is sent to any output.
+  #[arg(long = "dry-run")]
+  dry_run: bool,
   #[cfg(target_os = "linux")]
   /// Enable systemd watchdog notifications.  Enable only if run via systemd.
   #[arg(short, long, default_value_t, value_enum)]
@@ -99,132 +363,484 @@ struct Cli {
   mode: Mode,
   /// The interval in minutes for which statistics are gathered.  Must be between 1 and 15.
   #[arg(short, long, default_value_t = 5, value_parser=valid_interval)]
-  interval: u8
+  interval: u8,
+  /// How often `docker stats` is sampled, in seconds.  Must be between 1 and 300.
+  #[arg(long = "sample-period", default_value_t = 5, value_parser=valid_period)]
+  sample_period: u16,
+  /// The number of seconds after which `docker stats` is killed and the sample skipped.
+  #[arg(long = "docker-timeout", default_value_t = 30)]
+  docker_timeout: u64,
+  /// Only publish containers matching the filter, as `name=<pattern>`, `image=<pattern>` or `label:<key>=<pattern>`.
+  /// Patterns are globs, or regular expressions when prefixed with `re:`.  May be repeated.
+  #[arg(long = "include", value_parser = filter)]
+  include: Vec<Filter>,
+  /// Do not publish containers matching the filter, as for `--include`.  May be repeated.
+  #[arg(long = "exclude", value_parser = filter)]
+  exclude: Vec<Filter>,
+  /// A container label to publish to QuestDB as a symbol column, e.g. `team`.  May be repeated.
+  #[arg(long = "label-symbol")]
+  label_symbols: Vec<String>,
+  /// How often samples are published in raw mode, in seconds.  Must be between 1 and 3600.
+  #[arg(long = "raw-flush", default_value_t = 10, value_parser=valid_flush)]
+  raw_flush: u16,
+  /// The maximum number of samples buffered in raw mode before they are published.
+  #[arg(long = "raw-batch", default_value_t = 5000)]
+  raw_batch: usize,
+  /// Optional StatsD/DogStatsD server to also publish gauges to.  Either `udp://host:port` or `unix:///path/to/socket`.
+  #[arg(long = "statsd")]
+  statsd: Option<String>,
+  /// The prefix for metric names published to StatsD.
+  #[arg(long = "statsd-prefix", default_value = "container")]
+  statsd_prefix: String,
+  /// How often metrics are sent to StatsD.
+  #[arg(long = "statsd-frequency", default_value_t, value_enum)]
+  statsd_frequency: Frequency,
+  /// Optional address to listen on for StatsD metrics from applications, e.g. `0.0.0.0:8125`.
+  #[arg(long = "statsd-listen")]
+  statsd_listen: Option<String>,
+  /// The series name to publish application metrics received by the StatsD listener to.
+  #[arg(long = "app-table", default_value = "appStats")]
+  app_table: String,
+  /// The series name to publish the agent's own statistics to.
+  #[arg(long = "agent-table", default_value = "agentStats")]
+  agent_table: String,
+  /// Optional address to serve the `/healthz`, `/readyz` and `/status` HTTP endpoints on, e.g. `0.0.0.0:8080`.
+  #[arg(long = "health-listen")]
+  health_listen: Option<String>,
+  /// The number of seconds without a collection after which `/healthz` reports the agent as unhealthy.
+  /// Must be longer than the sample period.
+  #[arg(long = "health-timeout", default_value_t = 60)]
+  health_timeout: u64,
+  /// Optional OpenTelemetry collector endpoint to export metrics to, e.g. `http://localhost:4318`.
+  #[arg(long = "otlp-endpoint")]
+  otlp_endpoint: Option<String>,
+  /// The OTLP transport protocol to use.
+  #[arg(long = "otlp-protocol", default_value_t, value_enum)]
+  otlp_protocol: OtlpProtocol,
+  /// Optional headers to send with OTLP exports, as `key=value`.  May be repeated.
+  #[arg(long = "otlp-header")]
+  otlp_headers: Vec<String>,
+  /// Optional InfluxDB compatible endpoint to also write line protocol to, e.g. `http://localhost:8086` or `udp://localhost:8089`.
+  #[arg(long = "influx-url")]
+  influx_url: Option<String>,
+  /// The InfluxDB HTTP write API version.
+  #[arg(long = "influx-version", default_value_t, value_enum)]
+  influx_version: InfluxVersion,
+  /// The database to write to with the v1 API.
+  #[arg(long = "influx-database", default_value = "containerstatsd")]
+  influx_database: String,
+  /// The user name for the v1 API.
+  #[arg(long = "influx-username")]
+  influx_username: Option<String>,
+  /// The password for the v1 API.
+  #[arg(long = "influx-password")]
+  influx_password: Option<String>,
+  /// The organisation to write to with the v2 API.
+  #[arg(long = "influx-org")]
+  influx_org: Option<String>,
+  /// The bucket to write to with the v2 API.
+  #[arg(long = "influx-bucket", default_value = "containerstatsd")]
+  influx_bucket: String,
+  /// The API token, sent as a `Token` authorization header.
+  #[arg(long = "influx-token")]
+  influx_token: Option<String>,
+  /// The timestamp precision for line protocol.
+  #[arg(long = "influx-precision", default_value_t, value_enum)]
+  influx_precision: Precision,
+  /// How special characters in measurement, tag and field names are handled.
+  #[arg(long = "influx-escape", default_value_t, value_enum)]
+  influx_escape: Escape,
+  /// Optional Graphite/carbon plaintext listener to also publish to, as `host:port`.
+  #[arg(long = "graphite")]
+  graphite: Option<String>,
+  /// The prefix for Graphite metric paths.
+  #[arg(long = "graphite-prefix", default_value = "containers")]
+  graphite_prefix: String,
+  /// The Graphite metric path template.  Supports `{prefix}`, `{host}`, `{container}`, `{name}`, `{id}` and `{metric}`.
+  #[arg(long = "graphite-template", default_value = "{prefix}.{host}.{name}.{metric}")]
+  graphite_template: String,
+  /// Publish Graphite tagged series (`prefix.metric;host=..;container=..;name=..`) instead of the path template.
+  #[arg(long = "graphite-tagged")]
+  graphite_tagged: bool,
+  /// Optional file to write JSON lines records to.  Use `-` for stdout.
+  #[arg(long = "json")]
+  json: Option<String>,
+  /// Rotate the JSON lines file when it reaches this size in MiB.
+  #[arg(long = "json-max-size")]
+  json_max_size: Option<u64>,
+  /// Rotate the JSON lines file periodically.
+  #[arg(long = "json-rotation", default_value_t, value_enum)]
+  json_rotation: Rotation,
+  /// The number of rotated JSON lines files to keep.
+  #[arg(long = "json-retain", default_value_t = 7)]
+  json_retain: usize,
+  /// Optional directory to archive interval data to, partitioned by day.
+  #[arg(long = "archive-dir")]
+  archive_dir: Option<String>,
+  /// The file format for archived data.
+  #[arg(long = "archive-format", default_value_t, value_enum)]
+  archive_format: ArchiveFormat,
+  /// The number of times publishing to a sink is retried before the interval's data is dropped for that sink.
+  #[arg(long = "retries", default_value_t = 3)]
+  retries: u8,
+  /// The number of seconds to wait on shutdown for the partial interval to be published.
+  #[arg(long = "shutdown-timeout", default_value_t = 30)]
+  shutdown_timeout: u64,
+  /// The number of batches that may wait to be published.  Must be between 1 and 1024.
+  #[arg(long = "queue-depth", default_value_t = 4, value_parser = valid_depth)]
+  queue_depth: usize,
+  /// What to do with a batch when the publish queue is full.
+  #[arg(long = "overflow", default_value_t, value_enum)]
+  overflow: Overflow,
+  /// The directory batches are spooled to with the spool overflow policy.
+  #[arg(long = "spool-dir", default_value = "/var/spool/container-statsd")]
+  spool_dir: String
 }
 
-fn next_publish(interval: u8) -> DateTime<Utc>
+/// The `CONTAINER_STATSD_` environment variable for the long argument, e.g.
+/// `CONTAINER_STATSD_STATS_TABLE`.
+fn variable(long: &str) -> String
 {
-  Utc::now().duration_round_up(TimeDelta::try_minutes(interval as i64).unwrap()).unwrap()
+  format!("CONTAINER_STATSD_{}", long.to_uppercase().replace('-', "_"))
 }
 
-fn timeout() -> Duration
+/// Whether the option takes a comma separated list from the environment.  Destinations,
+/// filters and OTLP headers may themselves contain commas, so take a single value.
+fn delimited(arg: &clap::Arg) -> bool
 {
-  #[cfg(target_os = "linux")]
-  return daemon::watchdog_enabled(false).expect("watchdog timeout not set");
-  
-  #[cfg(target_os = "macos")]
-  return Duration::from_secs(10);
+  matches!(arg.get_action(), ArgAction::Append) && !["destinations", "include", "exclude", "otlp_headers"].contains(&arg.get_id().as_str())
 }
 
-#[cfg(target_os = "linux")]
-fn notify_watchdog(notified: &mut DateTime<Utc>, timeout: Duration)
+/// Name each option's environment variable in its help.  The variables are read by
+/// `config::arguments`, rather than by clap, so that only the environment is comma separated.
+fn environment(command: clap::Command) -> clap::Command
 {
-  let now = Utc::now();
-  let diff = now - *notified;
-  if diff.num_milliseconds() > (timeout.as_millis() / 2) as i64
+  command.mut_args(|arg|
+      {
+        let Some(long) = arg.get_long() else { return arg; };
+        let help = format!("{} [env: {}]", arg.get_help().map(|h| h.to_string()).unwrap_or_default(), variable(long));
+        arg.help(help)
+      })
+}
+
+/// Parse the command line, environment and configuration file.  The command line takes
+/// precedence over the environment, which takes precedence over the configuration file.
+fn parse(args: Vec<OsString>) -> Result<App, clap::Error>
+{
+  let matches = App::command().mut_subcommand("run", environment).try_get_matches_from(args)?;
+  App::from_arg_matches(&matches)
+}
+
+/// The command line arguments, with `run` inserted when no subcommand is specified so that
+/// existing service units, and deployments configured through the environment, continue to work.
+fn arguments() -> Vec<OsString>
+{
+  let mut args : Vec<OsString> = std::env::args_os().collect();
+  let first = args.get(1).and_then(|a| a.to_str()).unwrap_or_default().to_string();
+  let known = ["run", "snapshot", "top", "help", "-h", "--help", "-V", "--version"];
+  if !known.contains(&first.as_str()) { args.insert(1, OsString::from("run")); }
+  args
+}
+
+fn next_publish(args: &Cli) -> DateTime<Utc>
+{
+  if args.mode == Mode::Raw { return Utc::now() + TimeDelta::seconds(args.raw_flush as i64); }
+  Utc::now().duration_round_up(TimeDelta::try_minutes(args.interval as i64).unwrap()).unwrap()
+}
+
+/// Drop the containers excluded by `--include` and `--exclude`, and attach the `--label-symbol`
+/// labels.  Containers are inspected when filtering by image or label, or attaching labels.  When
+/// only the labels need inspecting and it fails, the containers are returned without labels.
+fn prepare(args: &Cli, records: Vec<Stats>, term: &Arc<AtomicBool>) -> error::Result<Vec<Stats>>
+{
+  let labels = !args.label_symbols.is_empty();
+  if !labels && args.include.is_empty() && args.exclude.is_empty() { return Ok(records); }
+  let timeout = Duration::from_secs(args.docker_timeout);
+  let metadata = if filter::inspect(&[&args.include, &args.exclude]) { collector::metadata(&records, timeout, term)? }
+    else if labels
+    {
+      // Only the labels are missing, so publish the statistics rather than drop them.
+      collector::metadata(&records, timeout, term).unwrap_or_else(|e|
+          {
+            warn!("Failed to inspect containers, publishing without labels. {}", e);
+            HashMap::new()
+          })
+    }
+    else { HashMap::new() };
+
+  let mut records = filter::apply(&args.include, &args.exclude, records, &metadata);
+  if labels
+  {
+    for stat in &mut records
+    {
+      let Some(metadata) = metadata.get(&stat.id) else { continue; };
+      stat.labels = args.label_symbols.iter().filter_map(|k| metadata.labels.get(k).map(|v| (k.clone(), v.clone()))).collect();
+    }
+  }
+  Ok(records)
+}
+
+fn app_metrics(aggregator: Option<&Arc<Mutex<Aggregator>>>) -> Vec<Metric>
+{
+  let Some(aggregator) = aggregator else { return Vec::new(); };
+  let mut agg = aggregator.lock().unwrap_or_else(|e| e.into_inner());
+  let metrics = agg.take();
+  let (parsed, rejected) = agg.counts();
+  info!("Aggregated {} application metrics ({} parsed, {} rejected since start)", metrics.len(), parsed, rejected);
+  metrics
+}
+
+/// Check the options that cannot be validated when parsed alone.
+fn validate(args: &Cli) -> error::Result<()>
+{
+  ilp::label_columns(&args.label_symbols)?;
+  // `/healthz` would fail between samples.
+  if args.health_listen.is_some() && args.health_timeout <= args.sample_period as u64
   {
-    let _sent = daemon::notify(false, &[NotifyState::Watchdog]).expect("notify failed");
-    *notified = now;
-    debug!("Sent watchdog notification");
+    return Err(Error::Config(format!("The health timeout of {}s must be longer than the sample period of {}s.",
+      args.health_timeout, args.sample_period)));
   }
+  Ok(())
 }
 
-fn statistics() -> Vec<Stats>
+/// Re-read the configuration and rebuild the sinks.  Samples gathered so far are kept, and
+/// a batch already being published is still delivered by the previous sinks.
+fn reconfigure(args: &mut Cli, publisher: &Publisher, systemd: &Systemd)
 {
-  let output = Command::new("docker").arg("stats").arg("--no-stream").arg("--format=json").output().expect("failed to execute process");
-  let mut reader = BufReader::new(output.stdout.as_slice());
-  let mut vec : Vec<Stats> = Vec::with_capacity(32);
-  let mut line = String::new();
-  while reader.read_line(&mut line).unwrap() > 0
+  systemd.reloading();
+  match config::reload()
   {
-    let raw : RawStats = serde_json::from_str(&line.trim()).expect("failed to parse json");
-    let stats = createStats(&raw);
-    vec.push(stats);
-    line.clear();
+    Ok(updated) if let Err(e) = validate(&Cli{health_listen: args.health_listen.clone(), health_timeout: args.health_timeout, ..updated.clone()}) =>
+    {
+      // The health endpoint is only configured on start.
+      warn!("Failed to reload configuration, continuing with the current configuration. {}", e);
+    },
+    Ok(mut updated) =>
+    {
+      if updated.statsd_listen != args.statsd_listen { warn!("Changes to the StatsD listener take effect on restart."); }
+      updated.statsd_listen = args.statsd_listen.clone();
+      if updated.health_listen != args.health_listen || updated.health_timeout != args.health_timeout { warn!("Changes to the health endpoint take effect on restart."); }
+      updated.health_listen = args.health_listen.clone();
+      updated.health_timeout = args.health_timeout;
+      #[cfg(target_os = "linux")]
+      if updated.watchdog != args.watchdog { warn!("Changes to the watchdog take effect on restart."); }
+      #[cfg(target_os = "linux")]
+      { updated.watchdog = args.watchdog; }
+
+      if updated.queue_depth != args.queue_depth || updated.overflow != args.overflow || updated.spool_dir != args.spool_dir
+      {
+        warn!("Changes to the publish queue take effect on restart.");
+      }
+      updated.queue_depth = args.queue_depth;
+      updated.overflow = args.overflow;
+      updated.spool_dir = args.spool_dir.clone();
+
+      *args = updated;
+      publisher.sinks(Sinks::new(args));
+      info!("Reloaded configuration for {}", args.host);
+    },
+    Err(e) => warn!("Failed to reload configuration, continuing with the current configuration. {}", e)
   }
+  systemd.ready();
+}
 
-  vec
+/// Sleep until the instant, returning early when terminated.
+fn sleep_until(term: &Arc<AtomicBool>, until: Instant)
+{
+  while !term.load(Ordering::Relaxed)
+  {
+    let now = Instant::now();
+    if now >= until { return; }
+    systemd::progress();
+    thread::sleep(min(until - now, Duration::from_millis(250)));
+  }
 }
 
-fn publish_stats(args: &Cli, term: &Arc<AtomicBool>, interval: Duration) -> Option<JoinHandle<()>>
+/// Schedule the next sample one period after the previous one, on the monotonic clock.  When
+/// collection overruns the period, the missed samples are skipped so that the schedule is kept.
+fn schedule(args: &Cli, next: &mut Instant, elapsed: Duration)
 {
-  let mut handle : Option<JoinHandle<()>> = None;
-  let published = next_publish(args.interval);
-  info!("Publishing stats at {:?} for {} with watchdog interval {}", published, args.host, interval.as_secs());
+  let period = Duration::from_secs(args.sample_period as u64);
+  let now = Instant::now();
+  *next += period;
+  if *next > now { return; }
 
-  #[cfg(target_os = "linux")]
-  let mut notified = Utc::now();
+  let missed = ((now - *next).as_millis() / period.as_millis()) as u32 + 1;
+  *next += period * missed;
+  agent::skipped(missed);
+  warn!("Collection took {:.1}s, overrunning the {}s sample period.  Skipped {} sample(s).", elapsed.as_secs_f64(), period.as_secs(), missed);
+}
+
+fn publish_stats(args: &mut Cli, term: &Arc<AtomicBool>, reload: &Arc<AtomicBool>, systemd: &Systemd, aggregator: Option<&Arc<Mutex<Aggregator>>>,
+  publisher: &Publisher, next: &mut Instant)
+{
+  let since = Utc::now();
+  let mut published = next_publish(args);
+  agent::interval(published);
+  info!("Publishing stats at {:?} for {}", published, args.host);
 
-  let size = (args.interval as usize) * 60 * 32;
+  let size = if args.mode == Mode::Raw { args.raw_batch } else { (args.interval as usize) * 60 * 32 };
   let mut vec : Vec<Stats> = Vec::with_capacity(size);
   while !term.load(Ordering::Relaxed)
   {
-    let records = statistics();
-    vec.extend(records);
-    debug!("Gathered {:?} statistics for {}", vec.len(), args.host);
+    sleep_until(term, *next);
+    if term.load(Ordering::Relaxed) { break; }
 
-    #[cfg(target_os = "linux")]
-    if args.watchdog == Watchdog::Enabled { notify_watchdog(&mut notified, interval); }
+    if reload.swap(false, Ordering::Relaxed)
+    {
+      reconfigure(args, publisher, systemd);
+      published = next_publish(args);
+      agent::interval(published);
+    }
 
-    if Utc::now() > published
+    let raw = args.mode == Mode::Raw;
+    let started = Instant::now();
+    let result = collector::statistics(Duration::from_secs(args.docker_timeout), term).and_then(|records| prepare(args, records, term));
+    schedule(args, next, started.elapsed());
+    systemd::progress();
+    let records = match result
     {
-      if vec.len() > 0
+      Ok(records) => records,
+      Err(e) =>
       {
-        let copy = args.clone();
-        handle = Some(thread::spawn(move ||
-            {
-              let data = gather(&copy, vec);
-              publish(&copy, data, published).expect("Failed to publish stats");
-            }));
-
-        info!("Publishing stats at {:?} for {}", published, args.host);
-        #[cfg(target_os = "linux")]
-        if args.watchdog == Watchdog::Enabled { notify_watchdog(&mut notified, interval); }
-        break;
+        warn!("{}", e);
+        systemd.status(&e.to_string());
+        continue;
       }
+    };
+    if args.statsd_frequency == Frequency::Sample && !args.dry_run && let Err(e) = statsd::publish(args, &records)
+    {
+      warn!("Failed to publish stats to StatsD. {}", e);
+    }
+    vec.extend(records);
+    debug!("Gathered {:?} statistics for {}", vec.len(), args.host);
+
+    if (Utc::now() > published || (raw && vec.len() >= args.raw_batch)) && !vec.is_empty()
+    {
+      let time = if raw { Utc::now() } else { published };
+      enqueue(args, publisher, aggregator, vec, since, time, coverage(args, published, published));
+
+      info!("Publishing stats at {:?} for {}", published, args.host);
+      return;
     }
   }
-  
-  handle
+
+  if vec.is_empty() { return; }
+  let now = Utc::now();
+  let (time, coverage) = if args.mode == Mode::Raw { (now, 1.0) } else { (published, coverage(args, published, now)) };
+  info!("Publishing the partial interval at {:?} for {} before shutting down, {:.0}% of the interval covered", time, args.host, coverage * 100.0);
+  enqueue(args, publisher, aggregator, vec, since, time, coverage);
 }
 
-fn main() -> Result<(), Error>
+/// The share of the interval ending at `published` that is covered by samples gathered until
+/// `end`.  Samples are only gathered from when the agent started.
+fn coverage(args: &Cli, published: DateTime<Utc>, end: DateTime<Utc>) -> f64
 {
-  let term = Arc::new(AtomicBool::new(false));
-  signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term)).expect("Error setting SIGTERM handler");
-  signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term)).expect("Error setting SIGTERM handler");
-  
-  let args = Cli::parse();
-  simple_logger::init_with_env().unwrap();
-  
-  #[cfg(target_os = "linux")]
-  let duration =
-      {
-        if args.watchdog == Watchdog::Enabled { timeout() }
-        else { Duration::from_secs(0) }
-      };
-  #[cfg(target_os = "macos")]
-  let duration = timeout();
+  if args.mode == Mode::Raw { return 1.0; }
+  let length = TimeDelta::minutes(args.interval as i64);
+  let start = max(published - length, agent::status().started);
+  ((end - start).num_milliseconds() as f64 / length.num_milliseconds() as f64).clamp(0.0, 1.0)
+}
+
+/// The number of seconds the batch covers.  In raw mode this is the time since the batch was
+/// started at `since`, as it is published early when full.  Otherwise it is the covered share
+/// of the interval.
+fn duration(args: &Cli, since: DateTime<Utc>, coverage: f64) -> f64
+{
+  if args.mode == Mode::Raw { return (Utc::now() - since).num_milliseconds() as f64 / 1000.0; }
+  coverage * (args.interval as f64) * 60.0
+}
+
+/// Aggregate the samples gathered since `since` and queue them to be published at `time`.
+fn enqueue(args: &Cli, publisher: &Publisher, aggregator: Option<&Arc<Mutex<Aggregator>>>, vec: Vec<Stats>, since: DateTime<Utc>,
+  time: DateTime<Utc>, coverage: f64)
+{
+  let metrics = app_metrics(aggregator);
+  let agent = agent::take();
+  publisher.push(Batch{time, stats: gather(args.mode, vec, time), disks: disks(&args.disks), metrics, agent, coverage,
+    duration: duration(args, since, coverage)});
+}
+
+fn run(mut args: Cli, term: Arc<AtomicBool>, reload: Arc<AtomicBool>) -> error::Result<()>
+{
+  if let Err(e) = simple_logger::init_with_env() { eprintln!("Failed to initialise logging. {}", e); }
   
-  #[cfg(target_os = "linux")]
-  if args.watchdog == Watchdog::Enabled && !daemon::booted() {
-    panic!("Not running systemd, early exit.");
-  };
+  let systemd = Systemd::new(&args)?;
+  validate(&args)?;
 
-  #[cfg(target_os = "linux")]
-  if args.watchdog == Watchdog::Enabled { let _ = daemon::notify(false, &[NotifyState::Ready]).expect("notify failed"); }
+  let aggregator = args.statsd_listen.as_ref().map(|_| Arc::new(Mutex::new(Aggregator::new())));
+  let listener = match (&args.statsd_listen, &aggregator)
+  {
+    (Some(address), Some(agg)) => Some(listener::listen(address, Arc::clone(agg), Arc::clone(&term))?),
+    _ => None
+  };
+  let health = match &args.health_listen
+  {
+    Some(address) => Some(health::serve(address, args.health_timeout, Arc::clone(&term))?),
+    None => None
+  };
 
-  let mut handle : Option<JoinHandle<()>> = None;
+  let reporter = systemd.clone();
+  let publisher = Publisher::new(&args, Sinks::new(&args), Box::new(move || reporter.published()), Arc::clone(&term)).
+      map_err(|e| Error::Config(format!("Failed to create spool directory {}. {}", args.spool_dir, e)))?;
+  systemd.ready();
+  let watchdog = systemd.watchdog(Arc::clone(&term));
   
+  let mut next = Instant::now();
   while !term.load(Ordering::Relaxed)
   {
-    handle = publish_stats(&args, &term, duration);
+    publish_stats(&mut args, &term, &reload, &systemd, aggregator.as_ref(), &publisher, &mut next);
   }
 
-  #[cfg(target_os = "linux")]
-  if args.watchdog == Watchdog::Enabled { let _sent = daemon::notify(true, &[NotifyState::Stopping]).expect("notify failed"); }
-  
-  if handle.is_some() { handle.take().unwrap().join().unwrap(); }
+  // Wait for the queued batches, extending the systemd stop timeout to match.
+  let timeout = Duration::from_secs(args.shutdown_timeout);
+  systemd.stopping(timeout);
+  publisher.shutdown(timeout);
+  if let Some(watchdog) = watchdog && watchdog.join().is_err() { warn!("Watchdog thread panicked."); }
+  if let Some(listener) = listener && listener.join().is_err() { warn!("StatsD listener thread panicked."); }
+  if let Some(health) = health && health.join().is_err() { warn!("Health check thread panicked."); }
 
   Ok(())
-}
\ No newline at end of file
+}
+
+fn start() -> error::Result<()>
+{
+  let term = Arc::new(AtomicBool::new(false));
+  signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
+  signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
+  let reload = Arc::new(AtomicBool::new(false));
+  signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))?;
+
+  match parse(config::arguments(arguments(), |name| std::env::var(name).ok()).map_err(Error::Config)?).unwrap_or_else(|e| e.exit()).command
+  {
+    Commands::Run(args) => run(*args, term, reload),
+    Commands::Snapshot(args) => snapshot::run(&args, &term),
+    Commands::Top(args) => top::run(&args, &term)
+  }
+}
+
+/// Exits non-zero only when the agent cannot start, transient failures are logged and retried.
+fn main() -> ExitCode
+{
+  match start()
+  {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(e) =>
+    {
+      eprintln!("{}", e);
+      ExitCode::FAILURE
+    }
+  }
+}
+
+/// The `run` options for the arguments, for tests.
+#[cfg(test)]
+fn cli(args: &[&str]) -> Cli
+{
+  let args : Vec<OsString> = ["container-statsd", "run"].iter().chain(args).map(OsString::from).collect();
+  match parse(args).expect("Invalid arguments").command
+  {
+    Commands::Run(cli) => *cli,
+    _ => unreachable!()
+  }
+}
@@ -0,0 +1,329 @@
+use std::time::Duration;
+use log::info;
+use opentelemetry_proto::tonic::{
+  collector::metrics::v1::{ExportMetricsServiceRequest, metrics_service_client::MetricsServiceClient},
+  common::v1::{AnyValue, InstrumentationScope, KeyValue, any_value},
+  metrics::v1::{
+    Gauge,
+    Metric,
+    NumberDataPoint,
+    ResourceMetrics,
+    ScopeMetrics,
+    metric::Data,
+    number_data_point
+  },
+  resource::v1::Resource
+};
+use prost::Message;
+use tonic::metadata::{MetadataKey, MetadataValue};
+
+use super::{Cli, OtlpProtocol};
+use super::agent;
+use super::sink::{Batch, Sink};
+use super::stats::Stats;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn attribute(key: &str, value: &str) -> KeyValue
+{
+  KeyValue{key: key.to_string(), value: Some(AnyValue{value: Some(any_value::Value::StringValue(value.to_string()))}), ..Default::default()}
+}
+
+fn point(value: f64, time: u64, attributes: Vec<KeyValue>) -> NumberDataPoint
+{
+  NumberDataPoint{attributes, time_unix_nano: time, value: Some(number_data_point::Value::AsDouble(value)), ..Default::default()}
+}
+
+fn gauge(name: &str, unit: &str, description: &str, value: f64, time: u64) -> Metric
+{
+  Metric{name: name.to_string(), unit: unit.to_string(), description: description.to_string(),
+    data: Some(Data::Gauge(Gauge{data_points: vec![point(value, time, vec![])]})), ..Default::default()}
+}
+
+/// IO totals split by direction.  Docker reports totals since the container started, but these
+/// are aggregated over the interval like the other statistics, so are not valid cumulative sums
+/// and are exported as gauges.
+fn io(name: &str, description: &str, attribute_name: &str, values: [(&str, f64); 2], time: u64) -> Metric
+{
+  let data_points = values.iter().map(|(direction, value)| point(*value, time, vec![attribute(attribute_name, direction)])).collect();
+  Metric{name: name.to_string(), unit: "By".to_string(), description: description.to_string(),
+    data: Some(Data::Gauge(Gauge{data_points})), ..Default::default()}
+}
+
+/// Map the aggregated statistics to OTLP metrics, one resource per container, following the
+/// OpenTelemetry container semantic conventions.
+pub fn request(cli: &Cli, stats: &[Stats]) -> ExportMetricsServiceRequest
+{
+  let scope = InstrumentationScope{name: env!("CARGO_PKG_NAME").to_string(), version: env!("CARGO_PKG_VERSION").to_string(), ..Default::default()};
+
+  let resource_metrics = stats.iter().map(|stat|
+      {
+        let nanos = stat.time.timestamp_nanos_opt().unwrap_or_default() as u64;
+        let resource = Resource{attributes: vec![
+          attribute("host.name", &cli.host),
+          attribute("container.id", &stat.id),
+          attribute("container.name", &stat.name)
+        ], ..Default::default()};
+
+        let metrics = vec![
+          gauge("container.cpu.usage", "{cpu}", "Container CPU usage in number of CPUs.", stat.cpuPercentage / 100.0, nanos),
+          gauge("container.memory.usage", "By", "Memory usage of the container.", stat.memoryUsage.bytes(), nanos),
+          gauge("container.memory.limit", "By", "Memory limit of the container.", stat.totalMemory.bytes(), nanos),
+          gauge("container.memory.utilization", "1", "Memory usage as a fraction of the limit.", stat.memoryPercentage / 100.0, nanos),
+          gauge("container.pids", "{process}", "Number of processes or threads in the container.", stat.pids as f64, nanos),
+          io("container.network.io", "Network bytes for the container.", "network.io.direction",
+            [("receive", stat.netIO.incoming.bytes()), ("transmit", stat.netIO.outgoing.bytes())], nanos),
+          io("container.disk.io", "Disk bytes for the container.", "disk.io.direction",
+            [("read", stat.blockIO.incoming.bytes()), ("write", stat.blockIO.outgoing.bytes())], nanos)
+        ];
+
+        ResourceMetrics{resource: Some(resource), scope_metrics: vec![ScopeMetrics{scope: Some(scope.clone()), metrics, ..Default::default()}], ..Default::default()}
+      }).collect();
+
+  ExportMetricsServiceRequest{resource_metrics}
+}
+
+fn headers(cli: &Cli) -> Vec<(&str, &str)>
+{
+  cli.otlp_headers.iter().filter_map(|h| h.split_once('=')).map(|(k, v)| (k.trim(), v.trim())).collect()
+}
+
+fn http(cli: &Cli, endpoint: &str, request: &ExportMetricsServiceRequest) -> Result<(), String>
+{
+  let url = if endpoint.trim_end_matches('/').ends_with("/v1/metrics") { endpoint.to_string() }
+    else { format!("{}/v1/metrics", endpoint.trim_end_matches('/')) };
+
+  let agent = ureq::Agent::config_builder().timeout_global(Some(TIMEOUT)).build().new_agent();
+  let mut req = agent.post(url.as_str()).header("Content-Type", "application/x-protobuf");
+  for (key, value) in headers(cli) { req = req.header(key, value); }
+  let body = request.encode_to_vec();
+  req.send(&body).map_err(|e| format!("OTLP/HTTP export to {} failed. {}", url, e))?;
+  agent::sent(body.len());
+  Ok(())
+}
+
+/// The error followed by its causes, as transport errors only describe themselves generically.
+fn causes(e: &dyn std::error::Error) -> String
+{
+  let mut message = e.to_string();
+  let mut source = e.source();
+  while let Some(error) = source
+  {
+    let cause = error.to_string();
+    if !message.contains(&cause) { message.push_str(&format!(": {}", cause)); }
+    source = error.source();
+  }
+  message
+}
+
+/// Export over gRPC, with TLS for `https://` endpoints.
+fn grpc(cli: &Cli, endpoint: &str, request: ExportMetricsServiceRequest) -> Result<(), String>
+{
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().map_err(|e| e.to_string())?;
+  runtime.block_on(async
+    {
+      let mut client = MetricsServiceClient::connect(endpoint.to_string()).await.
+          map_err(|e| format!("Failed to connect to OTLP/gRPC endpoint {}. {}", endpoint, causes(&e)))?;
+
+      let bytes = request.encoded_len();
+      let mut req = tonic::Request::new(request);
+      req.set_timeout(TIMEOUT);
+      for (key, value) in headers(cli)
+      {
+        let key = MetadataKey::from_bytes(key.to_lowercase().as_bytes()).map_err(|e| format!("Invalid OTLP header {}. {}", key, e))?;
+        let value = MetadataValue::try_from(value).map_err(|e| format!("Invalid OTLP header value for {}. {}", key, e))?;
+        req.metadata_mut().insert(key, value);
+      }
+
+      client.export(req).await.map_err(|e| format!("OTLP/gRPC export to {} failed. {}", endpoint, e))?;
+      agent::sent(bytes);
+      Ok(())
+    })
+}
+
+pub fn publish(cli: &Cli, stats: &[Stats]) -> Result<(), String>
+{
+  let endpoint = match &cli.otlp_endpoint { Some(endpoint) => endpoint, None => return Ok(()) };
+
+  let request = request(cli, stats);
+  match cli.otlp_protocol
+  {
+    OtlpProtocol::Http => http(cli, endpoint, &request)?,
+    OtlpProtocol::Grpc => grpc(cli, endpoint, request)?
+  }
+
+  info!("Published {:?} container statistics for {} to {}.", stats.len(), cli.host, endpoint);
+  Ok(())
+}
+
+/// Exports to an OpenTelemetry collector.
+pub struct Otlp
+{
+  cli: Cli
+}
+
+impl Otlp
+{
+  pub fn new(cli: &Cli) -> Otlp
+  {
+    Otlp{cli: cli.clone()}
+  }
+}
+
+impl Sink for Otlp
+{
+  fn name(&self) -> String
+  {
+    format!("OTLP {}", self.cli.otlp_endpoint.as_deref().unwrap_or_default())
+  }
+
+  fn publish(&mut self, batch: &Batch) -> Result<(), String>
+  {
+    publish(&self.cli, &batch.stats)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::sync::mpsc::{Sender, channel};
+  use std::thread;
+  use chrono::{DateTime, TimeDelta};
+  use opentelemetry_proto::tonic::collector::metrics::v1::{
+    ExportMetricsServiceResponse,
+    metrics_service_server::{MetricsService, MetricsServiceServer}
+  };
+  use tiny_http::{Response, Server};
+  use tonic::transport::server::TcpIncoming;
+
+  use super::*;
+  use super::super::cli;
+  use super::super::stats::Measurement;
+
+  fn stats() -> Vec<Stats>
+  {
+    let mut stat = Stats::new();
+    stat.id = "3f2a1b".to_string();
+    stat.name = "web".to_string();
+    stat.cpuPercentage = 150.0;
+    stat.memoryUsage = Measurement{value: 1.0, unit: "MiB".to_string()};
+    stat.netIO.incoming = Measurement{value: 2.0, unit: "KB".to_string()};
+    stat.netIO.outgoing = Measurement{value: 3.0, unit: "B".to_string()};
+    stat.time = DateTime::UNIX_EPOCH + TimeDelta::seconds(60);
+    vec![stat]
+  }
+
+  fn attributes(attributes: &[KeyValue]) -> Vec<(String, String)>
+  {
+    attributes.iter().map(|a| match a.value.as_ref().and_then(|v| v.value.as_ref())
+      {
+        Some(any_value::Value::StringValue(s)) => (a.key.clone(), s.clone()),
+        _ => (a.key.clone(), String::new())
+      }).collect()
+  }
+
+  /// A data point's value, time and attributes.
+  type Point = (f64, u64, Vec<(String, String)>);
+
+  fn points(metric: &Metric) -> Vec<Point>
+  {
+    let Some(Data::Gauge(gauge)) = &metric.data else { panic!("{} is not a gauge", metric.name); };
+    gauge.data_points.iter().map(|p| match p.value
+      {
+        Some(number_data_point::Value::AsDouble(v)) => (v, p.time_unix_nano, attributes(&p.attributes)),
+        _ => panic!("{} is not a double", metric.name)
+      }).collect()
+  }
+
+  fn check(request: &ExportMetricsServiceRequest)
+  {
+    assert_eq!(request.resource_metrics.len(), 1);
+    let resource = &request.resource_metrics[0];
+    assert_eq!(attributes(&resource.resource.as_ref().unwrap().attributes), vec![("host.name".to_string(), "node1".to_string()),
+      ("container.id".to_string(), "3f2a1b".to_string()), ("container.name".to_string(), "web".to_string())]);
+
+    let metrics = &resource.scope_metrics[0].metrics;
+    let metric = |name: &str| metrics.iter().find(|m| m.name == name).unwrap_or_else(|| panic!("No metric {}", name));
+    assert_eq!(points(metric("container.cpu.usage")), vec![(1.5, 60_000_000_000, vec![])]);
+    assert_eq!(points(metric("container.memory.usage"))[0].0, 1048576.0);
+    assert_eq!(points(metric("container.network.io")), vec![
+      (2048.0, 60_000_000_000, vec![("network.io.direction".to_string(), "receive".to_string())]),
+      (3.0, 60_000_000_000, vec![("network.io.direction".to_string(), "transmit".to_string())])]);
+    assert_eq!(metric("container.disk.io").unit, "By");
+  }
+
+  #[test]
+  fn request_maps_statistics_to_metrics()
+  {
+    check(&request(&cli(&["--node", "node1"]), &stats()));
+  }
+
+  #[test]
+  fn http_posts_protobuf_to_the_metrics_path()
+  {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let receiver = thread::spawn(move ||
+        {
+          let mut request = server.recv().unwrap();
+          let header = |name: &str| request.headers().iter().find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name)).map(|h| h.value.to_string());
+          let received = (request.url().to_string(), header("Content-Type"), header("Authorization"));
+          let mut body : Vec<u8> = Vec::new();
+          request.as_reader().read_to_end(&mut body).unwrap();
+          request.respond(Response::empty(200)).unwrap();
+          (received, body)
+        });
+
+    let cli = cli(&["--node", "node1", "--otlp-endpoint", &endpoint, "--otlp-header", "Authorization=Bearer abc"]);
+    publish(&cli, &stats()).unwrap();
+
+    let ((url, content_type, authorization), body) = receiver.join().unwrap();
+    assert_eq!(url, "/v1/metrics");
+    assert_eq!(content_type.as_deref(), Some("application/x-protobuf"));
+    assert_eq!(authorization.as_deref(), Some("Bearer abc"));
+    check(&ExportMetricsServiceRequest::decode(body.as_slice()).unwrap());
+  }
+
+  /// A gRPC receiver stub, forwarding the authorization metadata and request it receives.
+  struct Receiver
+  {
+    received: Sender<(Option<String>, ExportMetricsServiceRequest)>
+  }
+
+  #[tonic::async_trait]
+  impl MetricsService for Receiver
+  {
+    async fn export(&self, request: tonic::Request<ExportMetricsServiceRequest>) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status>
+    {
+      let authorization = request.metadata().get("authorization").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+      self.received.send((authorization, request.into_inner())).map_err(|e| tonic::Status::internal(e.to_string()))?;
+      Ok(tonic::Response::new(ExportMetricsServiceResponse::default()))
+    }
+  }
+
+  #[test]
+  fn grpc_exports_to_the_metrics_service()
+  {
+    let (received, requests) = channel();
+    let (address, bound) = channel();
+    thread::spawn(move ||
+        {
+          let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+          runtime.block_on(async
+            {
+              let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+              address.send(incoming.local_addr().unwrap()).unwrap();
+              tonic::transport::Server::builder().add_service(MetricsServiceServer::new(Receiver{received})).
+                  serve_with_incoming(incoming).await.unwrap();
+            });
+        });
+
+    let endpoint = format!("http://{}", bound.recv().unwrap());
+    let cli = cli(&["--node", "node1", "--otlp-endpoint", &endpoint, "--otlp-protocol", "grpc", "--otlp-header", "Authorization=Bearer abc"]);
+    publish(&cli, &stats()).unwrap();
+
+    let (authorization, request) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(authorization.as_deref(), Some("Bearer abc"));
+    check(&request);
+  }
+}
@@ -0,0 +1,596 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Error, Result};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+use super::{Cli, Overflow};
+use super::agent;
+use super::sink::{Batch, Sinks, Slot};
+use super::systemd;
+
+/// Called after each publish, e.g. to report the outcome to systemd.
+pub type Report = Box<dyn Fn() + Send + Sync>;
+
+/// The delivery of a batch to the outputs, recorded once every output has published, dropped
+/// or spooled it.
+struct Delivery
+{
+  remaining: AtomicUsize,
+  /// Whether any output published or dropped the batch, rather than spooling it.
+  resolved: AtomicBool,
+  failed: AtomicBool,
+  /// The longest time taken by an output to publish the batch, in microseconds.
+  longest: AtomicU64
+}
+
+impl Delivery
+{
+  fn new(outputs: usize) -> Arc<Delivery>
+  {
+    Arc::new(Delivery{remaining: AtomicUsize::new(outputs), resolved: AtomicBool::new(false), failed: AtomicBool::new(false),
+      longest: AtomicU64::new(0)})
+  }
+
+  /// Record an output's outcome, `None` when it spooled the batch to publish later.
+  fn done(&self, shared: &Shared, succeeded: Option<bool>, duration: Duration)
+  {
+    if let Some(succeeded) = succeeded
+    {
+      self.resolved.store(true, Ordering::Relaxed);
+      if !succeeded { self.failed.store(true, Ordering::Relaxed); }
+    }
+    self.longest.fetch_max(duration.as_micros() as u64, Ordering::Relaxed);
+    if self.remaining.fetch_sub(1, Ordering::AcqRel) != 1 { return; }
+
+    if self.resolved.load(Ordering::Relaxed)
+    {
+      agent::published(Duration::from_micros(self.longest.load(Ordering::Relaxed)), !self.failed.load(Ordering::Relaxed));
+      (shared.report)();
+    }
+    agent::dequeued();
+  }
+}
+
+/// A batch waiting to be published by an output.
+struct Item
+{
+  batch: Arc<Batch>,
+  delivery: Arc<Delivery>
+}
+
+struct Queue
+{
+  items: VecDeque<Item>,
+  /// No more batches will be pushed, the worker exits once the queue is empty.
+  closed: bool
+}
+
+/// An output's queue, published from by a worker thread of its own.
+struct Output
+{
+  name: String,
+  queue: Mutex<Queue>,
+  /// Signalled when a batch is pushed, the queue is closed or the sink is replaced.
+  available: Condvar,
+  /// Signalled when the worker takes a batch.
+  space: Condvar,
+  /// The sink from a reloaded configuration, used from the next batch.
+  replacement: Mutex<Option<Slot>>,
+  spool: PathBuf,
+  spooled: AtomicUsize,
+  sequence: AtomicU64,
+  /// Set when shutdown gives up waiting, the worker exits after the batch in flight.
+  abort: AtomicBool
+}
+
+struct Shared
+{
+  /// The outputs batches are pushed to.  Outputs removed by a reload are no longer listed,
+  /// but their workers publish what was queued before stopping.
+  outputs: Mutex<Vec<Arc<Output>>>,
+  depth: usize,
+  overflow: Overflow,
+  spool: PathBuf,
+  report: Report
+}
+
+struct Worker
+{
+  output: Arc<Output>,
+  handle: JoinHandle<()>
+}
+
+/// Publishes batches to each output in order, from a bounded queue and worker thread per
+/// output, so that a slow or unavailable output does not hold back or drop data for the
+/// others.  When an output's queue is full the overflow policy either drops its oldest batch,
+/// blocks the collection loop, or spools the batch to disk to be published once the queue has
+/// drained.
+pub struct Publisher
+{
+  shared: Arc<Shared>,
+  /// Set when the agent is terminating, a blocked push then gives up.
+  term: Arc<AtomicBool>,
+  workers: Mutex<Vec<Worker>>
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T>
+{
+  mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The spool directory name for each output, from its name.  Outputs with the same name are
+/// numbered in order.
+fn directories(slots: &[Slot]) -> Vec<String>
+{
+  let mut vec : Vec<String> = Vec::with_capacity(slots.len());
+  for slot in slots
+  {
+    let name : String = slot.name().chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+    let count = vec.iter().filter(|d| d.split('~').next() == Some(name.as_str())).count();
+    vec.push(if count == 0 { name } else { format!("{}~{}", name, count + 1) });
+  }
+  vec
+}
+
+impl Output
+{
+  /// The spooled batch files, oldest first.
+  fn spooled(&self) -> Vec<PathBuf>
+  {
+    let Ok(entries) = fs::read_dir(&self.spool) else { return Vec::new(); };
+    let mut files : Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).
+        filter(|p| p.extension().is_some_and(|e| e == "json")).collect();
+    files.sort();
+    files
+  }
+
+  fn spool(&self, batch: &Batch) -> Result<()>
+  {
+    fs::create_dir_all(&self.spool)?;
+    let nanos = batch.time.timestamp_nanos_opt().unwrap_or_default();
+    let path = self.spool.join(format!("{:020}-{:06}.json", nanos, self.sequence.fetch_add(1, Ordering::Relaxed)));
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_vec(batch).map_err(Error::from)?)?;
+    fs::rename(&temporary, &path)?;
+    self.spooled.fetch_add(1, Ordering::Relaxed);
+    // The spooled batch waits to be published as a batch of its own.
+    agent::enqueued();
+    Ok(())
+  }
+
+  /// Take the oldest spooled batch.  Files that cannot be read are logged and removed.
+  fn unspool(&self) -> Option<Batch>
+  {
+    for path in self.spooled()
+    {
+      let batch = fs::read(&path).and_then(|content| serde_json::from_slice::<Batch>(&content).map_err(Error::from));
+      if let Err(e) = fs::remove_file(&path) { warn!("Failed to remove spooled batch {}. {}", path.display(), e); }
+      self.spooled.fetch_sub(1, Ordering::Relaxed);
+      match batch
+      {
+        Ok(batch) => return Some(batch),
+        Err(e) =>
+        {
+          warn!("Discarding spooled batch {}. {}", path.display(), e);
+          agent::dequeued();
+        }
+      }
+    }
+    None
+  }
+
+  /// The next batch to publish, waiting for one if necessary.  Spooled batches are published
+  /// once the queue is empty.  Returns `None` when the queue has been closed and drained.
+  fn next(&self, shared: &Shared) -> Option<Item>
+  {
+    loop
+    {
+      {
+        let mut queue = lock(&self.queue);
+        if self.abort.load(Ordering::Relaxed) { return None; }
+        if let Some(item) = queue.items.pop_front()
+        {
+          self.space.notify_all();
+          drop(queue);
+          shared.status();
+          return Some(item);
+        }
+        if queue.closed { return None; }
+      }
+
+      if self.spooled.load(Ordering::Relaxed) > 0 && let Some(batch) = self.unspool()
+      {
+        shared.status();
+        return Some(Item{batch: Arc::new(batch), delivery: Delivery::new(1)});
+      }
+
+      let queue = lock(&self.queue);
+      if queue.items.is_empty() && !queue.closed
+      {
+        let _ = self.available.wait_timeout(queue, Duration::from_secs(1));
+      }
+    }
+  }
+
+  /// Queue the batch, applying the overflow policy when the queue is full.  A blocked push
+  /// gives up once the agent is terminating and drops the batch.
+  fn push(&self, shared: &Shared, item: Item, term: &AtomicBool)
+  {
+    let mut queue = lock(&self.queue);
+    if queue.items.len() >= shared.depth
+    {
+      match shared.overflow
+      {
+        Overflow::DropOldest =>
+        {
+          if let Some(dropped) = queue.items.pop_front()
+          {
+            warn!("Publish queue for {} full, dropped the batch for {}.", self.name, dropped.batch.time);
+            agent::dropped();
+            drop(queue);
+            dropped.delivery.done(shared, Some(false), Duration::ZERO);
+            queue = lock(&self.queue);
+          }
+        },
+        Overflow::Block =>
+        {
+          warn!("Publish queue for {} full, waiting to queue the batch for {}.", self.name, item.batch.time);
+          while queue.items.len() >= shared.depth && !queue.closed && !term.load(Ordering::Relaxed)
+          {
+            // Waiting for the publisher is not a stall of the collection loop.
+            systemd::progress();
+            queue = self.space.wait_timeout(queue, Duration::from_millis(100)).unwrap_or_else(|e| e.into_inner()).0;
+          }
+          if queue.items.len() >= shared.depth && !queue.closed
+          {
+            drop(queue);
+            warn!("Shutting down with the publish queue for {} full, dropped the batch for {}.", self.name, item.batch.time);
+            agent::dropped();
+            item.delivery.done(shared, Some(false), Duration::ZERO);
+            return;
+          }
+        },
+        Overflow::Spool =>
+        {
+          drop(queue);
+          match self.spool(&item.batch)
+          {
+            Ok(()) =>
+            {
+              info!("Publish queue for {} full, spooled the batch for {} to {}.", self.name, item.batch.time, self.spool.display());
+              item.delivery.done(shared, None, Duration::ZERO);
+              return;
+            },
+            Err(e) => warn!("Publish queue for {} full and failed to spool the batch for {}, queueing it anyway. {}", self.name, item.batch.time, e)
+          }
+          queue = lock(&self.queue);
+        }
+      }
+    }
+
+    queue.items.push_back(item);
+    self.available.notify_one();
+  }
+
+  fn close(&self)
+  {
+    lock(&self.queue).closed = true;
+    self.available.notify_all();
+    self.space.notify_all();
+  }
+}
+
+impl Shared
+{
+  /// Record the longest output queue and the number of spooled batches.
+  fn status(&self)
+  {
+    let outputs = lock(&self.outputs).clone();
+    let queued = outputs.iter().map(|o| lock(&o.queue).items.len()).max().unwrap_or_default();
+    agent::queue(queued, outputs.iter().map(|o| o.spooled.load(Ordering::Relaxed)).sum());
+  }
+
+  /// Start the output's worker.  With the spool overflow policy, batches spooled by a previous
+  /// run are published once the queue is empty.
+  fn start(self: &Arc<Shared>, mut slot: Slot, directory: &str) -> Worker
+  {
+    let output = Arc::new(Output{name: slot.name(), queue: Mutex::new(Queue{items: VecDeque::with_capacity(self.depth), closed: false}),
+      available: Condvar::new(), space: Condvar::new(), replacement: Mutex::new(None), spool: self.spool.join(directory),
+      spooled: AtomicUsize::new(0), sequence: AtomicU64::new(0), abort: AtomicBool::new(false)});
+
+    if self.overflow == Overflow::Spool
+    {
+      let spooled = output.spooled().len();
+      output.spooled.store(spooled, Ordering::Relaxed);
+      for _ in 0..spooled { agent::enqueued(); }
+      if spooled > 0 { info!("Publishing {} batches spooled in {} to {}", spooled, output.spool.display(), output.name); }
+    }
+
+    let shared = Arc::clone(self);
+    let worker = Arc::clone(&output);
+    let handle = thread::spawn(move ||
+        {
+          while let Some(item) = worker.next(&shared)
+          {
+            if let Some(replacement) = lock(&worker.replacement).take() { slot.update(replacement); }
+            let started = Instant::now();
+            let succeeded = catch_unwind(AssertUnwindSafe(|| slot.publish(&item.batch))).unwrap_or_else(|_|
+                {
+                  warn!("Publishing the batch for {} to {} panicked, the batch was dropped.", item.batch.time, worker.name);
+                  false
+                });
+            item.delivery.done(&shared, Some(succeeded), started.elapsed());
+          }
+        });
+    Worker{output, handle}
+  }
+}
+
+impl Publisher
+{
+  /// Start a worker for each sink.  With the spool overflow policy, batches are spooled to a
+  /// directory per sink under the spool directory.
+  pub fn new(cli: &Cli, sinks: Sinks, report: Report, term: Arc<AtomicBool>) -> Result<Publisher>
+  {
+    let spool = PathBuf::from(&cli.spool_dir);
+    if cli.overflow == Overflow::Spool { fs::create_dir_all(&spool)?; }
+
+    let shared = Arc::new(Shared{outputs: Mutex::new(Vec::new()), depth: cli.queue_depth, overflow: cli.overflow, spool, report});
+    let slots = sinks.into_slots();
+    let workers : Vec<Worker> = directories(&slots).iter().zip(slots).map(|(directory, slot)| shared.start(slot, directory)).collect();
+    *lock(&shared.outputs) = workers.iter().map(|w| Arc::clone(&w.output)).collect();
+    shared.status();
+    Ok(Publisher{shared, term, workers: Mutex::new(workers)})
+  }
+
+  /// Publish the batch to each output once those queued before it have been published.  With
+  /// the block overflow policy this waits for space in each queue until the agent terminates.
+  pub fn push(&self, batch: Batch)
+  {
+    let outputs = lock(&self.shared.outputs).clone();
+    if outputs.is_empty() { return; }
+
+    agent::enqueued();
+    let batch = Arc::new(batch);
+    let delivery = Delivery::new(outputs.len());
+    for output in &outputs { output.push(&self.shared, Item{batch: Arc::clone(&batch), delivery: Arc::clone(&delivery)}, &self.term); }
+    self.shared.status();
+  }
+
+  /// Replace the sinks used for batches published from now on.  A sink with the same name as
+  /// an existing one takes over its queue, the batch in flight is still published by the
+  /// previous sink.  Sinks no longer configured publish what was queued for them and stop.
+  pub fn sinks(&self, sinks: Sinks)
+  {
+    let mut workers = lock(&self.workers);
+    let mut previous = lock(&self.shared.outputs).clone();
+    let mut outputs : Vec<Arc<Output>> = Vec::new();
+
+    let slots = sinks.into_slots();
+    for (directory, slot) in directories(&slots).iter().zip(slots)
+    {
+      match previous.iter().position(|o| o.name == slot.name())
+      {
+        Some(i) =>
+        {
+          let output = previous.remove(i);
+          *lock(&output.replacement) = Some(slot);
+          outputs.push(output);
+        },
+        None =>
+        {
+          let worker = self.shared.start(slot, directory);
+          outputs.push(Arc::clone(&worker.output));
+          workers.push(worker);
+        }
+      }
+    }
+
+    for output in previous
+    {
+      info!("{} is no longer configured, publishing its queued batches before stopping.", output.name);
+      output.close();
+    }
+    workers.retain(|w| !w.handle.is_finished());
+    *lock(&self.shared.outputs) = outputs;
+    self.shared.status();
+  }
+
+  /// Publish the queued batches and stop, waiting at most for the timeout.  Batches still
+  /// queued after the timeout are spooled with the spool overflow policy, otherwise dropped.
+  pub fn shutdown(self, timeout: Duration)
+  {
+    let workers = self.workers.into_inner().unwrap_or_else(|e| e.into_inner());
+    for worker in &workers
+    {
+      let queued = lock(&worker.output.queue).items.len();
+      if queued > 0 { info!("Publishing {} queued batches to {} before shutting down", queued, worker.output.name); }
+      worker.output.close();
+    }
+
+    let started = Instant::now();
+    while workers.iter().any(|w| !w.handle.is_finished()) && started.elapsed() < timeout { thread::sleep(Duration::from_millis(100)); }
+
+    let shared = &self.shared;
+    for worker in workers
+    {
+      let output = &worker.output;
+      if worker.handle.is_finished()
+      {
+        if worker.handle.join().is_err() { warn!("Publisher thread for {} panicked, queued batches may not have been published.", output.name); }
+        continue;
+      }
+
+      output.abort.store(true, Ordering::Relaxed);
+      let remaining : Vec<Item> = lock(&output.queue).items.drain(..).collect();
+      warn!("Gave up waiting {}s for {}, {} batches not published.", timeout.as_secs(), output.name, remaining.len());
+      for item in remaining
+      {
+        if shared.overflow == Overflow::Spool
+        {
+          match output.spool(&item.batch)
+          {
+            Ok(()) =>
+            {
+              item.delivery.done(shared, None, Duration::ZERO);
+              continue;
+            },
+            Err(e) => warn!("Failed to spool the batch for {} to {}. {}", item.batch.time, output.name, e)
+          }
+        }
+        warn!("Dropped the batch for {} to {}.", item.batch.time, output.name);
+        agent::dropped();
+        item.delivery.done(shared, Some(false), Duration::ZERO);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::process;
+  use std::sync::mpsc::{Receiver, Sender, channel};
+  use chrono::{DateTime, TimeDelta, Utc};
+
+  use super::*;
+  use super::super::agent::AgentStats;
+  use super::super::cli;
+  use super::super::sink::Sink;
+
+  /// The times of the batches published by a recorder.
+  type Times = Arc<Mutex<Vec<DateTime<Utc>>>>;
+
+  /// Records the time of each batch it publishes.
+  struct Recorder
+  {
+    name: String,
+    times: Times,
+    /// When set, each publish waits for a message first.
+    gate: Option<Receiver<()>>
+  }
+
+  impl Sink for Recorder
+  {
+    fn name(&self) -> String
+    {
+      self.name.clone()
+    }
+
+    fn publish(&mut self, batch: &Batch) -> std::result::Result<(), String>
+    {
+      if let Some(gate) = &self.gate { gate.recv().map_err(|e| e.to_string())?; }
+      lock(&self.times).push(batch.time);
+      Ok(())
+    }
+  }
+
+  fn recorder(name: &str, gated: bool) -> (Box<dyn Sink>, Times, Option<Sender<()>>)
+  {
+    let times = Arc::new(Mutex::new(Vec::new()));
+    let (sender, receiver) = channel();
+    let sink = Recorder{name: name.to_string(), times: Arc::clone(&times), gate: gated.then_some(receiver)};
+    (Box::new(sink), times, gated.then_some(sender))
+  }
+
+  fn batch(seconds: i64) -> Batch
+  {
+    Batch{time: DateTime::UNIX_EPOCH + TimeDelta::seconds(seconds), stats: Vec::new(), disks: Vec::new(), metrics: Vec::new(),
+      agent: AgentStats::default(), coverage: 1.0, duration: 0.0}
+  }
+
+  fn seconds(times: &Times) -> Vec<i64>
+  {
+    lock(times).iter().map(|t| t.timestamp()).collect()
+  }
+
+  fn wait_for(times: &Times, count: usize)
+  {
+    let started = Instant::now();
+    while lock(times).len() < count && started.elapsed() < Duration::from_secs(5) { thread::sleep(Duration::from_millis(10)); }
+  }
+
+  #[test]
+  fn a_stalled_output_does_not_hold_back_the_others()
+  {
+    let (stalled, stalled_times, gate) = recorder("stalled", true);
+    let (healthy, healthy_times, _) = recorder("healthy", false);
+    let cli = cli(&["--queue-depth", "2", "--overflow", "drop-oldest"]);
+    let publisher = Publisher::new(&cli, Sinks::of(vec![stalled, healthy]), Box::new(|| {}), Arc::new(AtomicBool::new(false))).unwrap();
+
+    for i in 1..=6
+    {
+      publisher.push(batch(i));
+      wait_for(&healthy_times, i as usize);
+    }
+    assert_eq!(seconds(&healthy_times), vec![1, 2, 3, 4, 5, 6]);
+
+    // The stalled output kept the batch in flight and the newest two, dropping the others.
+    let gate = gate.unwrap();
+    for _ in 0..3 { gate.send(()).unwrap(); }
+    publisher.shutdown(Duration::from_secs(5));
+    assert_eq!(seconds(&stalled_times), vec![1, 5, 6]);
+  }
+
+  #[test]
+  fn overflowing_batches_are_spooled_per_output()
+  {
+    let dir = std::env::temp_dir().join(format!("container-statsd-{}-spool", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let cli = cli(&["--queue-depth", "1", "--overflow", "spool", "--spool-dir", &dir.to_string_lossy()]);
+
+    let (stalled, stalled_times, gate) = recorder("stalled", true);
+    let publisher = Publisher::new(&cli, Sinks::of(vec![stalled]), Box::new(|| {}), Arc::new(AtomicBool::new(false))).unwrap();
+    publisher.push(batch(1));
+    thread::sleep(Duration::from_millis(100));
+    for i in 2..=4 { publisher.push(batch(i)); }
+    assert_eq!(fs::read_dir(dir.join("stalled")).unwrap().count(), 2);
+
+    // Batches still queued when giving up are spooled, and published after a restart.
+    publisher.shutdown(Duration::from_millis(200));
+    drop(gate);
+    assert_eq!(seconds(&stalled_times), Vec::<i64>::new());
+    assert_eq!(fs::read_dir(dir.join("stalled")).unwrap().count(), 3);
+
+    let (restarted, restarted_times, _) = recorder("stalled", false);
+    let publisher = Publisher::new(&cli, Sinks::of(vec![restarted]), Box::new(|| {}), Arc::new(AtomicBool::new(false))).unwrap();
+    wait_for(&restarted_times, 3);
+    publisher.shutdown(Duration::from_secs(5));
+    assert_eq!(seconds(&restarted_times), vec![2, 3, 4]);
+  }
+
+  #[test]
+  fn a_blocked_push_returns_once_terminating()
+  {
+    let (stalled, stalled_times, gate) = recorder("stalled", true);
+    let cli = cli(&["--queue-depth", "1", "--overflow", "block"]);
+    let term = Arc::new(AtomicBool::new(false));
+    let publisher = Publisher::new(&cli, Sinks::of(vec![stalled]), Box::new(|| {}), Arc::clone(&term)).unwrap();
+    publisher.push(batch(1));
+    thread::sleep(Duration::from_millis(100));
+    publisher.push(batch(2));
+
+    let terminate = Arc::clone(&term);
+    let signal = thread::spawn(move ||
+        {
+          thread::sleep(Duration::from_millis(200));
+          terminate.store(true, Ordering::Relaxed);
+        });
+    let started = Instant::now();
+    publisher.push(batch(3));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    signal.join().unwrap();
+
+    // The batch that could not be queued was dropped, the others are still published.
+    let gate = gate.unwrap();
+    for _ in 0..2 { gate.send(()).unwrap(); }
+    publisher.shutdown(Duration::from_secs(5));
+    assert_eq!(seconds(&stalled_times), vec![1, 2]);
+  }
+}
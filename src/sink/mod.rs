@@ -0,0 +1,164 @@
+use std::cmp::min;
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::Cli;
+use super::agent::{self, AgentStats};
+use super::archive::Archive;
+use super::graphite::Graphite;
+use super::ilp::{DryRun, QuestDB, destinations};
+use super::influx::Influx;
+use super::jsonl::JsonLines;
+use super::listener::Metric;
+use super::otlp::Otlp;
+use super::stats::{DiskStats, Stats};
+use super::statsd::StatsD;
+
+/// Maximum delay between retries of a failing sink.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The data gathered for an interval, delivered to every sink.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Batch
+{
+  pub time: DateTime<Utc>,
+  pub stats: Vec<Stats>,
+  pub disks: Vec<DiskStats>,
+  pub metrics: Vec<Metric>,
+  pub agent: AgentStats,
+  /// The share of the interval covered by the statistics.  Less than 1 for the first interval
+  /// after starting and for the partial interval published on shutdown.
+  pub coverage: f64,
+  /// The number of seconds covered by the batch, from which counter rates are computed.
+  #[serde(default)]
+  pub duration: f64
+}
+
+impl Batch
+{
+  /// The coverage, if the batch covers only part of the interval.
+  pub fn partial(&self) -> Option<f64>
+  {
+    (self.coverage < 1.0).then_some(self.coverage)
+  }
+}
+
+/// An output that receives each interval's data.
+pub trait Sink: Send
+{
+  /// Name used to identify the sink when reporting errors.
+  fn name(&self) -> String;
+
+  fn publish(&mut self, batch: &Batch) -> Result<(), String>;
+}
+
+/// A sink together with its retry state.
+pub struct Slot
+{
+  sink: Box<dyn Sink>,
+  retries: u8,
+  failures: u32
+}
+
+impl Slot
+{
+  pub fn name(&self) -> String
+  {
+    self.sink.name()
+  }
+
+  /// Replace the sink with the one from a reloaded configuration, keeping the retry state.
+  pub fn update(&mut self, slot: Slot)
+  {
+    self.sink = slot.sink;
+    self.retries = slot.retries;
+  }
+
+  /// Publish the batch, retrying with exponential backoff.  The initial delay grows with the
+  /// number of consecutive failed intervals, so an unavailable sink is not retried aggressively.
+  /// Returns false if the batch was dropped.
+  pub fn publish(&mut self, batch: &Batch) -> bool
+  {
+    let retries = self.retries;
+    let name = self.sink.name();
+    let mut backoff = min(Duration::from_secs(1 << min(self.failures, 5)), MAX_BACKOFF);
+
+    for attempt in 0..=retries
+    {
+      match self.sink.publish(batch)
+      {
+        Ok(()) =>
+        {
+          if self.failures > 0 { info!("Published to {} after {} failed intervals.", name, self.failures); }
+          self.failures = 0;
+          return true;
+        },
+        Err(e) if attempt < retries =>
+        {
+          warn!("Failed to publish to {} (attempt {} of {}), retrying in {}s. {}", name, attempt + 1, retries + 1, backoff.as_secs(), e);
+          agent::retried();
+          thread::sleep(backoff);
+          backoff = min(backoff * 2, MAX_BACKOFF);
+        },
+        Err(e) =>
+        {
+          warn!("Failed to publish to {}, dropping data for {:?}. {}", name, batch.time, e);
+          agent::failed();
+        }
+      }
+    }
+
+    self.failures += 1;
+    false
+  }
+}
+
+/// The configured sinks.  Each is published to by the publisher from its own queue, so a slow
+/// or failing sink does not delay or drop data for the others.
+pub struct Sinks
+{
+  slots: Vec<Slot>
+}
+
+impl Sinks
+{
+  pub fn new(cli: &Cli) -> Sinks
+  {
+    let mut sinks : Vec<Box<dyn Sink>> = Vec::new();
+    if cli.dry_run
+    {
+      for destination in destinations(cli) { sinks.push(Box::new(DryRun::new(cli, destination))); }
+      info!("Dry run, only rendering the QuestDB payload.  Nothing will be published.");
+      return Sinks{slots: sinks.into_iter().map(|sink| Slot{sink, retries: 0, failures: 0}).collect()};
+    }
+
+    if !cli.no_questdb
+    {
+      for destination in destinations(cli) { sinks.push(Box::new(QuestDB::new(cli, destination))); }
+    }
+    if cli.statsd.is_some() { sinks.push(Box::new(StatsD::new(cli))); }
+    if cli.otlp_endpoint.is_some() { sinks.push(Box::new(Otlp::new(cli))); }
+    if cli.influx_url.is_some() { sinks.push(Box::new(Influx::new(cli))); }
+    if cli.graphite.is_some() { sinks.push(Box::new(Graphite::new(cli))); }
+    if cli.json.is_some() { sinks.push(Box::new(JsonLines::new(cli))); }
+    if cli.archive_dir.is_some() { sinks.push(Box::new(Archive::new(cli))); }
+
+    info!("Publishing to {}", sinks.iter().map(|s| s.name()).collect::<Vec<String>>().join(", "));
+    Sinks{slots: sinks.into_iter().map(|sink| Slot{sink, retries: cli.retries, failures: 0}).collect()}
+  }
+
+  /// The given sinks, for tests.
+  #[cfg(test)]
+  pub fn of(sinks: Vec<Box<dyn Sink>>) -> Sinks
+  {
+    Sinks{slots: sinks.into_iter().map(|sink| Slot{sink, retries: 0, failures: 0}).collect()}
+  }
+
+  pub fn into_slots(self) -> Vec<Slot>
+  {
+    self.slots
+  }
+}
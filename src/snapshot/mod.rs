@@ -0,0 +1,175 @@
+use std::cmp::max;
+use std::io::{self, ErrorKind, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use super::{Format, Mode, Snapshot, sleep_until};
+use super::collector::{DEFAULT_TIMEOUT, statistics};
+use super::error::Result;
+use super::ilp::gather;
+use super::jsonl::{ContainerRecord, DiskRecord};
+use super::stats::{DiskStats, Stats, disks};
+
+/// The JSON output, with the same container and disk records as the JSON lines output.
+#[derive(Serialize)]
+struct Document<'a>
+{
+  time: DateTime<Utc>,
+  host: &'a str,
+  mode: Mode,
+  samples: usize,
+  containers: Vec<ContainerRecord<'a>>,
+  disks: Vec<DiskRecord<'a>>
+}
+
+/// Format a byte count using binary units, as `docker stats` does.
+pub fn human(bytes: f64) -> String
+{
+  const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let mut value = bytes;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < UNITS.len() - 1
+  {
+    value /= 1024.0;
+    unit += 1;
+  }
+
+  if unit == 0 { format!("{:.0}{}", value, UNITS[unit]) }
+  else { format!("{:.2}{}", value, UNITS[unit]) }
+}
+
+/// Lay out rows as left aligned columns separated by two spaces.
+pub fn table(headers: &[&str], rows: &[Vec<String>]) -> Vec<String>
+{
+  let mut widths : Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+  for row in rows
+  {
+    for (i, cell) in row.iter().enumerate() { widths[i] = widths[i].max(cell.chars().count()); }
+  }
+
+  let line = |cells: Vec<&str>| -> String
+  {
+    let padded : Vec<String> = cells.iter().enumerate().map(|(i, c)| format!("{:<width$}", c, width = widths[i])).collect();
+    padded.join("  ").trim_end().to_string()
+  };
+
+  let mut lines : Vec<String> = Vec::with_capacity(rows.len() + 1);
+  lines.push(line(headers.to_vec()));
+  for row in rows { lines.push(line(row.iter().map(|c| c.as_str()).collect())); }
+  lines
+}
+
+fn containers(mode: Mode, stats: &[Stats]) -> Vec<String>
+{
+  let mut headers = vec!["NAME", "CONTAINER", "CPU %", "MEM USAGE / LIMIT", "MEM %", "NET I/O", "BLOCK I/O", "PIDS"];
+  if mode == Mode::Raw { headers.insert(0, "TIME"); }
+
+  let rows : Vec<Vec<String>> = stats.iter().map(|s|
+      {
+        let mut row = vec![
+          s.name.clone(),
+          s.container.clone(),
+          format!("{:.2}%", s.cpuPercentage),
+          format!("{} / {}", human(s.memoryUsage.bytes()), human(s.totalMemory.bytes())),
+          format!("{:.2}%", s.memoryPercentage),
+          format!("{} / {}", human(s.netIO.incoming.bytes()), human(s.netIO.outgoing.bytes())),
+          format!("{} / {}", human(s.blockIO.incoming.bytes()), human(s.blockIO.outgoing.bytes())),
+          s.pids.to_string()
+        ];
+        if mode == Mode::Raw { row.insert(0, s.time.format("%H:%M:%S").to_string()); }
+        row
+      }).collect();
+
+  table(&headers, &rows)
+}
+
+pub fn disk_usage(disks: &[DiskStats]) -> Vec<String>
+{
+  let rows : Vec<Vec<String>> = disks.iter().map(|d| vec![
+    d.name.clone(),
+    d.mountPoint.clone(),
+    d.kind.clone(),
+    human(d.availableSpace as f64),
+    human(d.totalSpace as f64),
+    format!("{:.2}%", d.percentage()),
+    human(d.readBytes as f64),
+    human(d.writtenBytes as f64)
+  ]).collect();
+
+  table(&["DISK", "MOUNT", "TYPE", "AVAILABLE", "TOTAL", "AVAILABLE %", "READ", "WRITTEN"], &rows)
+}
+
+/// Collect a single sample, or samples every sample period for the specified duration,
+/// aggregate them with the chosen mode and print the result.  Interrupting the collection prints what was gathered.
+pub fn run(args: &Snapshot, term: &Arc<AtomicBool>) -> Result<()>
+{
+  let duration = args.duration.map(Duration::from_secs);
+  let started = Instant::now();
+  let period = Duration::from_secs(args.sample_period as u64);
+  let mut next = started;
+  let mut samples = 0;
+  let mut vec : Vec<Stats> = Vec::with_capacity(32);
+
+  loop
+  {
+    match statistics(DEFAULT_TIMEOUT, term)
+    {
+      Ok(records) => vec.extend(records),
+      Err(_) if term.load(Ordering::Relaxed) && samples > 0 => break,
+      Err(e) => return Err(e)
+    }
+    samples += 1;
+
+    next = max(next + period, Instant::now());
+    match duration
+    {
+      Some(d) if next < started + d => sleep_until(term, next),
+      _ => break
+    }
+    if term.load(Ordering::Relaxed) { break; }
+  }
+
+  let time = Utc::now();
+  let mut stats = gather(args.mode, vec, time);
+  stats.sort_by(|a, b| a.name.cmp(&b.name).then(a.time.cmp(&b.time)));
+  let disks = disks(&args.disks);
+
+  // Stop quietly when the output is closed early, e.g. piped to `head`.
+  match print(args, samples, started.elapsed(), time, &stats, &disks)
+  {
+    Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+    result => Ok(result?)
+  }
+}
+
+fn print(args: &Snapshot, samples: usize, elapsed: Duration, time: DateTime<Utc>, stats: &[Stats], disks: &[DiskStats]) -> io::Result<()>
+{
+  let host = &args.host;
+  let mut out = io::stdout().lock();
+  match args.format
+  {
+    Format::Table =>
+    {
+      writeln!(out, "{} at {}, {} of {} sample(s) over {}s", host, time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        format!("{:?}", args.mode).to_lowercase(), samples, elapsed.as_secs())?;
+      writeln!(out)?;
+      for line in containers(args.mode, stats) { writeln!(out, "{}", line)?; }
+      if !disks.is_empty()
+      {
+        writeln!(out)?;
+        for line in disk_usage(disks) { writeln!(out, "{}", line)?; }
+      }
+    },
+    Format::Json =>
+    {
+      let document = Document{time, host, mode: args.mode, samples,
+        containers: stats.iter().map(|s| ContainerRecord::new(host, s)).collect(),
+        disks: disks.iter().map(|d| DiskRecord::new(host, d, time)).collect()};
+      writeln!(out, "{}", serde_json::to_string_pretty(&document).map_err(io::Error::from)?)?;
+    }
+  }
+  out.flush()
+}
@@ -1,5 +1,11 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use chrono::{DateTime, Utc};
 use substring::Substring;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sysinfo::Disks;
+
+use super::error::{Error, Result};
 
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
@@ -16,7 +22,7 @@ pub struct RawStats
   pub PIDs: String
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(non_snake_case)]
 pub struct Measurement
 {
@@ -30,9 +36,22 @@ impl Measurement
   {
     Measurement{value: 0.0, unit: String::new()}
   }
+
+  /// The measurement value normalised to bytes.
+  pub fn bytes(&self) -> f64
+  {
+    match self.unit.as_str()
+    {
+      "KB" | "KiB" => self.value * 1024.0,
+      "MB" | "MiB" => self.value * 1024.0 * 1024.0,
+      "GB" | "GiB" => self.value * 1024.0 * 1024.0 * 1024.0,
+      "TB" | "TiB" => self.value * 1024.0 * 1024.0 * 1024.0 * 1024.0,
+      _ => self.value
+    }
+  }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(non_snake_case)]
 pub struct IO
 {
@@ -48,7 +67,7 @@ impl IO
   }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(non_snake_case)]
 pub struct Stats
 {
@@ -62,6 +81,11 @@ pub struct Stats
   pub totalMemory: Measurement,
   pub netIO: IO,
   pub pids: u32,
+  /// When the sample was collected, or the interval for aggregated statistics.
+  pub time: DateTime<Utc>,
+  /// The values of the `--label-symbol` labels the container has.
+  #[serde(default)]
+  pub labels: BTreeMap<String, String>
 }
 
 impl Stats
@@ -70,69 +94,120 @@ impl Stats
   {
     Stats{id: String::new(), container: String::new(), name: String::new(), blockIO: IO::new(),
       cpuPercentage: 0.0, memoryPercentage: 0.0, memoryUsage: Measurement::new(),
-      totalMemory: Measurement::new(), netIO: IO::new(), pids: 0}
+      totalMemory: Measurement::new(), netIO: IO::new(), pids: 0, time: Utc::now(), labels: BTreeMap::new()}
   }
 }
 
+/// Usage statistics for a disk/block device.
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(non_snake_case)]
-pub fn createStats(rs: &RawStats) -> Stats
+pub struct DiskStats
 {
-  fn parseIO(value: &String, ioType: &str) -> IO
+  pub name: String,
+  pub fileSystem: String,
+  pub mountPoint: String,
+  pub kind: String,
+  pub availableSpace: u64,
+  pub totalSpace: u64,
+  pub readBytes: u64,
+  pub writtenBytes: u64
+}
+
+impl DiskStats
+{
+  /// Percentage of the total space that is available.  Published as `percentage_use`.
+  pub fn percentage(&self) -> f64
+  {
+    (self.availableSpace as f64)/(self.totalSpace as f64) * 100.0
+  }
+}
+
+/// Disk usage statistics for the named devices.
+pub fn disks(names: &[String]) -> Vec<DiskStats>
+{
+  let mut vec : Vec<DiskStats> = Vec::with_capacity(names.len());
+  if names.is_empty() { return vec; }
+
+  let disks = Disks::new_with_refreshed_list();
+  for disk in disks.list()
+  {
+    for name in names
+    {
+      if disk.name() == OsString::from(name)
+      {
+        vec.push(DiskStats{name: disk.name().to_string_lossy().to_string(),
+          fileSystem: disk.file_system().to_string_lossy().to_string(),
+          mountPoint: disk.mount_point().to_string_lossy().to_string(),
+          kind: disk.kind().to_string(), availableSpace: disk.available_space(), totalSpace: disk.total_space(),
+          readBytes: disk.usage().read_bytes, writtenBytes: disk.usage().written_bytes});
+      }
+    }
+  }
+
+  vec
+}
+
+#[allow(non_snake_case)]
+pub fn createStats(rs: &RawStats) -> Result<Stats>
+{
+  fn parseIO(value: &str, ioType: &str) -> Result<IO>
   {
     let mut io = IO::new();
     let parts : Vec<&str> = value.split(" / ").collect();
-    if parts.len() != 2 { return io; }
+    if parts.len() != 2 { return Ok(io); }
 
-    let parseMeasurement = |part: &str| -> Measurement
+    let parseMeasurement = |part: &str| -> Result<Measurement>
     {
+      let parse = |v: &str| v.parse::<f64>().map_err(|_| Error::Parse(format!("{}/{}", part, ioType)));
       if part.ends_with("GB") || part.ends_with("gB")
       {
         let v = part.substring(0, part.len() - 2);
-        return Measurement { value: v.parse::<f64>().expect(format!("Failed to parse {}/{}", part, ioType).as_str()), unit: "GB".to_string() };
+        return Ok(Measurement{value: parse(v)?, unit: "GB".to_string()});
       }
       if part.ends_with("MB") || part.ends_with("mB")
       {
         let v = part.substring(0, part.len() - 2);
-        return Measurement{value: v.parse::<f64>().expect(format!("Failed to parse {}/{}", part, ioType).as_str()), unit: "MB".to_string()};
+        return Ok(Measurement{value: parse(v)?, unit: "MB".to_string()});
       }
       if part.ends_with("KB") || part.ends_with("kB")
       {
         let v = part.substring(0, part.len() - 2);
-        return Measurement{value: v.parse::<f64>().expect(format!("Failed to parse {}/{}", part, ioType).as_str()), unit: "KB".to_string()};
+        return Ok(Measurement{value: parse(v)?, unit: "KB".to_string()});
       }
       if part.ends_with("B")
       {
         let v = part.substring(0, part.len() - 1);
-        return Measurement{value: v.parse::<f64>().expect(format!("Failed to parse {}/{}", part, ioType).as_str()), unit: "B".to_string()};
+        return Ok(Measurement{value: parse(v)?, unit: "B".to_string()});
       }
       
-      return Measurement::new();
+      Ok(Measurement::new())
     };
     
-    io.incoming = parseMeasurement(parts[0]);
-    io.outgoing = parseMeasurement(parts[1]);
-    io 
+    io.incoming = parseMeasurement(parts[0])?;
+    io.outgoing = parseMeasurement(parts[1])?;
+    Ok(io)
   }
   
-  fn parseMemory(value: &str) -> Measurement
+  fn parseMemory(value: &str) -> Result<Measurement>
   {
-    if value == "0B" || value.len() < 3 { return Measurement{value: 0.0, unit: "bytes".to_string()}; }
+    if value == "0B" || value.len() < 3 { return Ok(Measurement{value: 0.0, unit: "bytes".to_string()}); }
     let v = value.substring(0, value.len() - 3);
-    Measurement{ value: v.parse::<f64>().expect(format!("Failed to parse MemUsage {}/{}", v, value).as_str()), unit: value.substring(v.len(), value.len()).to_string() }
+    let parsed = v.parse::<f64>().map_err(|_| Error::Parse(format!("MemUsage {}/{}", v, value)))?;
+    Ok(Measurement{ value: parsed, unit: value.substring(v.len(), value.len()).to_string() })
   }
   
   let mut stats = Stats{ id: rs.ID.clone(), container: rs.Container.clone(), name: rs.Name.clone(), 
     blockIO: IO::new(), cpuPercentage: 0.0, memoryPercentage: 0.0, 
     memoryUsage: Measurement::new(), totalMemory: Measurement::new(),
-    netIO: IO::new(), pids: 0};
+    netIO: IO::new(), pids: 0, time: Utc::now(), labels: BTreeMap::new()};
 
-  stats.cpuPercentage = rs.CPUPerc.replace("%", "").parse::<f64>().expect(format!("Failed to parse CPUPerc {}", rs.CPUPerc).as_str());
-  stats.memoryPercentage = rs.MemPerc.replace("%", "").parse::<f64>().expect(format!("Failed to parse CPUPerc {}", rs.MemPerc).as_str());
-  stats.pids = rs.PIDs.parse::<u32>().expect(format!("Failed to parse PIDs {}", rs.PIDs).as_str());
-  stats.blockIO = parseIO(&rs.BlockIO, "BlockIO");
-  stats.netIO = parseIO(&rs.NetIO, "NetIO");
+  stats.cpuPercentage = rs.CPUPerc.replace("%", "").parse::<f64>().map_err(|_| Error::Parse(format!("CPUPerc {}", rs.CPUPerc)))?;
+  stats.memoryPercentage = rs.MemPerc.replace("%", "").parse::<f64>().map_err(|_| Error::Parse(format!("MemPerc {}", rs.MemPerc)))?;
+  stats.pids = rs.PIDs.parse::<u32>().map_err(|_| Error::Parse(format!("PIDs {}", rs.PIDs)))?;
+  stats.blockIO = parseIO(&rs.BlockIO, "BlockIO")?;
+  stats.netIO = parseIO(&rs.NetIO, "NetIO")?;
   
-  fn parseMemUsage(value: &String) -> Vec<&str>
+  fn parseMemUsage(value: &str) -> Vec<&str>
   {
     let parts : Vec<&str> = value.split(" / ").collect();
     if parts.len() > 1 { return parts; }
@@ -146,14 +221,14 @@ pub fn createStats(rs: &RawStats) -> Stats
   let parts = parseMemUsage(&rs.MemUsage);
   if parts.len() > 1
   {
-    let mem = parseMemory(parts[0]);
+    let mem = parseMemory(parts[0])?;
     stats.memoryUsage.value = mem.value;
     stats.memoryUsage.unit = mem.unit.clone();
     
-    let mem = parseMemory(parts[1]);
+    let mem = parseMemory(parts[1])?;
     stats.totalMemory.value = mem.value;
     stats.totalMemory.unit = mem.unit.clone();
   }
   
-  stats
-}
\ No newline at end of file
+  Ok(stats)
+}
@@ -0,0 +1,227 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use log::debug;
+
+use super::{Cli, Frequency};
+use super::agent;
+use super::sink::{Batch, Sink};
+use super::stats::Stats;
+
+/// Payload size that fits in a single UDP datagram on a standard ethernet MTU.
+const UDP_PAYLOAD: usize = 1432;
+/// Datadog agent default for datagrams sent over a unix domain socket.
+const UNIX_PAYLOAD: usize = 8192;
+
+enum Socket
+{
+  Udp(UdpSocket),
+  #[cfg(unix)]
+  Unix(UnixDatagram)
+}
+
+/// Client for a StatsD or DogStatsD server.  The address is either `udp://host:port` (the scheme
+/// may be omitted) or `unix:///path/to/socket`.
+pub struct Client
+{
+  socket: Socket,
+  payload: usize
+}
+
+impl Client
+{
+  pub fn connect(address: &str) -> Result<Client>
+  {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix://")
+    {
+      let socket = UnixDatagram::unbound()?;
+      socket.connect(path)?;
+      return Ok(Client{socket: Socket::Unix(socket), payload: UNIX_PAYLOAD});
+    }
+
+    let addr = address.strip_prefix("udp://").unwrap_or(address);
+    if addr.contains("://") { return Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported StatsD address {}", address))); }
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    Ok(Client{socket: Socket::Udp(socket), payload: UDP_PAYLOAD})
+  }
+
+  /// Send the metric lines, packing as many as fit into each datagram.  Returns the number of
+  /// bytes sent.
+  pub fn send(&self, lines: &[String]) -> Result<usize>
+  {
+    let mut sent = 0;
+    let mut packet = String::with_capacity(self.payload);
+    for line in lines
+    {
+      if !packet.is_empty() && packet.len() + line.len() + 1 > self.payload
+      {
+        sent += self.write(&packet)?;
+        packet.clear();
+      }
+
+      if !packet.is_empty() { packet.push('\n'); }
+      packet.push_str(line);
+    }
+
+    if !packet.is_empty() { sent += self.write(&packet)?; }
+    Ok(sent)
+  }
+
+  fn write(&self, packet: &str) -> Result<usize>
+  {
+    let sent = match &self.socket
+    {
+      Socket::Udp(socket) => socket.send(packet.as_bytes()),
+      #[cfg(unix)]
+      Socket::Unix(socket) => socket.send(packet.as_bytes())
+    }?;
+    agent::sent(sent);
+    Ok(sent)
+  }
+}
+
+/// Replace characters that have special meaning in the DogStatsD datagram format.
+fn sanitise(value: &str) -> String
+{
+  value.chars().map(|c| match c { '|' | ',' | '#' | ':' | '@' | '\n' | ' ' => '_', _ => c }).collect()
+}
+
+/// Format the statistics as DogStatsD gauges tagged with the host, container and name.
+pub fn lines(cli: &Cli, stats: &[Stats]) -> Vec<String>
+{
+  let mut vec : Vec<String> = Vec::with_capacity(stats.len() * 9);
+  let prefix = &cli.statsd_prefix;
+  let host = sanitise(&cli.host);
+
+  for stat in stats
+  {
+    let tags = format!("host:{},container:{},name:{}", host, sanitise(&stat.container), sanitise(&stat.name));
+    let mut gauge = |metric: &str, value: f64| vec.push(format!("{}.{}:{}|g|#{}", prefix, metric, value, tags));
+
+    gauge("cpu.percent", stat.cpuPercentage);
+    gauge("memory.percent", stat.memoryPercentage);
+    gauge("memory.usage", stat.memoryUsage.bytes());
+    gauge("memory.limit", stat.totalMemory.bytes());
+    gauge("pids", stat.pids as f64);
+    gauge("block_io.in", stat.blockIO.incoming.bytes());
+    gauge("block_io.out", stat.blockIO.outgoing.bytes());
+    gauge("net_io.in", stat.netIO.incoming.bytes());
+    gauge("net_io.out", stat.netIO.outgoing.bytes());
+  }
+
+  vec
+}
+
+pub fn publish(cli: &Cli, stats: &[Stats]) -> Result<()>
+{
+  let address = match &cli.statsd { Some(address) => address, None => return Ok(()) };
+
+  let client = Client::connect(address)?;
+  let sent = client.send(&lines(cli, stats))?;
+  debug!("Published {:?} container statistics ({} bytes) for {} to {}.", stats.len(), sent, cli.host, address);
+  Ok(())
+}
+
+/// Publishes the aggregated statistics to StatsD each interval.  Samples are published as they
+/// are collected by the main loop when the frequency is `sample`.
+pub struct StatsD
+{
+  cli: Cli
+}
+
+impl StatsD
+{
+  pub fn new(cli: &Cli) -> StatsD
+  {
+    StatsD{cli: cli.clone()}
+  }
+}
+
+impl Sink for StatsD
+{
+  fn name(&self) -> String
+  {
+    format!("StatsD {}", self.cli.statsd.as_deref().unwrap_or_default())
+  }
+
+  fn publish(&mut self, batch: &Batch) -> std::result::Result<(), String>
+  {
+    if self.cli.statsd_frequency != Frequency::Interval { return Ok(()); }
+    publish(&self.cli, &batch.stats).map_err(|e| e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use std::time::Duration;
+  use super::*;
+  use super::super::stats::Measurement;
+
+  fn receiver() -> (UdpSocket, String)
+  {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let address = format!("udp://{}", socket.local_addr().unwrap());
+    (socket, address)
+  }
+
+  fn datagram(socket: &UdpSocket) -> String
+  {
+    let mut buffer = [0u8; 65536];
+    let size = socket.recv(&mut buffer).unwrap();
+    String::from_utf8_lossy(&buffer[..size]).to_string()
+  }
+
+  #[test]
+  fn lines_are_tagged_gauges()
+  {
+    let cli = super::super::cli(&["--node", "host 1", "--statsd-prefix", "docker"]);
+    let mut stat = Stats::new();
+    stat.container = "3f2a1b".to_string();
+    stat.name = "web|1".to_string();
+    stat.cpuPercentage = 12.5;
+    stat.memoryUsage = Measurement{value: 2.0, unit: "KiB".to_string()};
+
+    let lines = lines(&cli, &[stat]);
+    assert_eq!(lines.len(), 9);
+    assert_eq!(lines[0], "docker.cpu.percent:12.5|g|#host:host_1,container:3f2a1b,name:web_1");
+    assert_eq!(lines[2], "docker.memory.usage:2048|g|#host:host_1,container:3f2a1b,name:web_1");
+  }
+
+  #[test]
+  fn send_delivers_lines()
+  {
+    let (socket, address) = receiver();
+    let client = Client::connect(&address).unwrap();
+    let lines = vec!["a:1|g".to_string(), "b:2|g".to_string()];
+
+    assert_eq!(client.send(&lines).unwrap(), 11);
+    assert_eq!(datagram(&socket), "a:1|g\nb:2|g");
+  }
+
+  #[test]
+  fn send_packs_lines_into_datagrams()
+  {
+    let (socket, address) = receiver();
+    let client = Client::connect(&address).unwrap();
+    let lines : Vec<String> = (0..40).map(|i| format!("container.metric{:02}:{}|g|#{}", i, i, "x".repeat(70))).collect();
+
+    let sent = client.send(&lines).unwrap();
+    let mut received : Vec<String> = Vec::new();
+    let mut total = 0;
+    while total < sent
+    {
+      let packet = datagram(&socket);
+      assert!(packet.len() <= UDP_PAYLOAD, "Datagram of {} bytes", packet.len());
+      total += packet.len();
+      received.extend(packet.split('\n').map(String::from));
+    }
+    assert!(sent > UDP_PAYLOAD);
+    assert_eq!(received, lines);
+  }
+}
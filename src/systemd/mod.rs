@@ -0,0 +1,155 @@
+#[cfg(target_os = "linux")]
+use std::env;
+use std::sync::{Arc, LazyLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+#[cfg(target_os = "linux")]
+use libsystemd::daemon::{self, NotifyState};
+
+use super::Cli;
+#[cfg(target_os = "linux")]
+use super::Watchdog;
+use super::agent;
+#[cfg(target_os = "linux")]
+use super::error::Error;
+use super::error::Result;
+
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+/// Milliseconds since `EPOCH` at which the collection loop last made progress.
+static PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Record that the collection loop is making progress.
+pub fn progress()
+{
+  PROGRESS.store(EPOCH.elapsed().as_millis() as u64, Ordering::Relaxed);
+}
+
+/// The time since the collection loop last made progress.
+fn idle() -> Duration
+{
+  EPOCH.elapsed().saturating_sub(Duration::from_millis(PROGRESS.load(Ordering::Relaxed)))
+}
+
+/// Notifications to systemd.  Nothing is sent unless the watchdog is enabled.
+#[derive(Clone, Debug, Default)]
+pub struct Systemd
+{
+  /// The watchdog timeout, when enabled.
+  timeout: Option<Duration>
+}
+
+impl Systemd
+{
+  /// Check that the agent is running as a `Type=notify` service with a watchdog when the
+  /// watchdog is enabled.
+  pub fn new(cli: &Cli) -> Result<Systemd>
+  {
+    #[cfg(target_os = "linux")]
+    if cli.watchdog == Watchdog::Enabled
+    {
+      let disable = "or run with --watchdog disabled";
+      if !daemon::booted() { return Err(Error::Config(format!("Not running under systemd.  Run as a systemd service, {}.", disable))); }
+      if env::var_os("NOTIFY_SOCKET").is_none()
+      {
+        return Err(Error::Config(format!("NOTIFY_SOCKET is not set.  Set Type=notify in the service unit, {}.", disable)));
+      }
+      if env::var_os("WATCHDOG_USEC").is_none()
+      {
+        return Err(Error::Config(format!("WATCHDOG_USEC is not set.  Set WatchdogSec in the service unit, {}.", disable)));
+      }
+      let timeout = daemon::watchdog_enabled(false).
+          ok_or_else(|| Error::Config(format!("WATCHDOG_USEC is invalid or WATCHDOG_PID is not this process, {}.", disable)))?;
+      info!("Watchdog enabled with a timeout of {}s", timeout.as_secs_f64());
+      return Ok(Systemd{timeout: Some(timeout)});
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = cli;
+    Ok(Systemd{timeout: None})
+  }
+
+  #[cfg(target_os = "linux")]
+  fn notify(&self, state: &[NotifyState])
+  {
+    if self.timeout.is_none() { return; }
+    if let Err(e) = daemon::notify(false, state) { warn!("Failed to notify systemd. {}", e); }
+  }
+
+  /// Startup is complete, or a reload has finished.
+  pub fn ready(&self)
+  {
+    #[cfg(target_os = "linux")]
+    self.notify(&[NotifyState::Ready]);
+  }
+
+  /// The configuration is being reloaded.  Followed by `ready` once reloaded.
+  pub fn reloading(&self)
+  {
+    #[cfg(target_os = "linux")]
+    self.notify(&[NotifyState::Reloading]);
+  }
+
+  /// The agent is stopping, and may take up to the timeout to publish what it has gathered.
+  pub fn stopping(&self, timeout: Duration)
+  {
+    #[cfg(target_os = "linux")]
+    self.notify(&[NotifyState::Stopping, NotifyState::Other(format!("EXTEND_TIMEOUT_USEC={}", timeout.as_micros()))]);
+    #[cfg(not(target_os = "linux"))]
+    let _ = timeout;
+  }
+
+  /// Report the agent's state, shown by `systemctl status`.
+  pub fn status(&self, message: &str)
+  {
+    #[cfg(target_os = "linux")]
+    self.notify(&[NotifyState::Status(message.to_string())]);
+    #[cfg(not(target_os = "linux"))]
+    let _ = message;
+  }
+
+  /// Report the outcome of the last publish, along with the number of containers.
+  pub fn published(&self)
+  {
+    let status = agent::status();
+    let Some(time) = status.last_publish else { return; };
+    let outcome = if status.last_publish_succeeded.unwrap_or(true) { "succeeded" } else { "failed, see the log" };
+    self.status(&format!("Last publish at {} {}.  {} containers.", time.format("%Y-%m-%d %H:%M:%S UTC"), outcome, status.containers));
+  }
+
+  /// Notify the watchdog from a separate thread while the collection loop shows progress
+  /// within the watchdog timeout.  A loop that stalls for longer is restarted by systemd.
+  pub fn watchdog(&self, term: Arc<AtomicBool>) -> Option<JoinHandle<()>>
+  {
+    let timeout = self.timeout?;
+    let systemd = self.clone();
+    progress();
+    Some(thread::spawn(move ||
+        {
+          let period = (timeout / 4).max(Duration::from_millis(100));
+          let mut stalled = false;
+          while !term.load(Ordering::Relaxed)
+          {
+            let idle = idle();
+            if idle < timeout
+            {
+              #[cfg(target_os = "linux")]
+              systemd.notify(&[NotifyState::Watchdog]);
+              if stalled { info!("Collection loop is progressing again, resumed watchdog notifications."); }
+              stalled = false;
+            }
+            else if !stalled
+            {
+              warn!("Collection loop has not progressed for {}s, withholding watchdog notifications.", idle.as_secs());
+              systemd.status(&format!("Collection loop stalled for {}s", idle.as_secs()));
+              stalled = true;
+            }
+
+            let started = Instant::now();
+            while started.elapsed() < period && !term.load(Ordering::Relaxed) { thread::sleep(Duration::from_millis(100)); }
+          }
+        }))
+  }
+}
@@ -0,0 +1,274 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::io::{self, Error, Stdout, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, TimeDelta, Utc};
+use crossterm::{
+  cursor,
+  event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+  execute,
+  queue,
+  style::Print,
+  terminal::{self, ClearType}
+};
+
+use super::{Column, Mode, Top, sleep_until};
+use super::collector::{DEFAULT_TIMEOUT, statistics};
+use super::error::Result;
+use super::ilp::gather;
+use super::snapshot::{disk_usage, human, table};
+use super::stats::{DiskStats, Stats, disks};
+
+/// A container's latest sample, with IO rates since the previous sample and aggregates over
+/// the interval.
+struct Row
+{
+  name: String,
+  container: String,
+  cpu: f64,
+  cpu_interval: f64,
+  memory: f64,
+  limit: f64,
+  memory_percentage: f64,
+  memory_interval: f64,
+  net_in: f64,
+  net_out: f64,
+  block_in: f64,
+  block_out: f64,
+  pids: u32
+}
+
+struct State
+{
+  args: Top,
+  sort: Column,
+  reverse: bool,
+  previous: HashMap<String, Stats>,
+  window: Vec<Stats>,
+  rows: Vec<Row>,
+  disks: Vec<DiskStats>,
+  updated: Option<DateTime<Utc>>,
+  /// The last collection failure, cleared by the next successful collection.
+  error: Option<String>
+}
+
+impl State
+{
+  fn new(args: &Top) -> State
+  {
+    State{args: args.clone(), sort: args.sort, reverse: false,
+      previous: HashMap::new(), window: Vec::new(), rows: Vec::new(), disks: Vec::new(), updated: None, error: None}
+  }
+
+  fn update(&mut self, records: Vec<Stats>)
+  {
+    let now = Utc::now();
+    let cutoff = now - TimeDelta::minutes(self.args.interval as i64);
+    self.window.retain(|s| s.time >= cutoff);
+    self.window.extend(records.iter().cloned());
+    let aggregates = gather(self.args.mode, self.window.clone(), now);
+
+    let mut rows : Vec<Row> = Vec::with_capacity(records.len());
+    for stat in &records
+    {
+      let previous = self.previous.get(&stat.name);
+      let rate = |f: fn(&Stats) -> f64| -> f64
+      {
+        let Some(prev) = previous else { return 0.0; };
+        let seconds = (stat.time - prev.time).num_milliseconds() as f64 / 1000.0;
+        if seconds > 0.0 { (f(stat) - f(prev)).max(0.0) / seconds } else { 0.0 }
+      };
+      let aggregate = aggregates.iter().rfind(|a| a.name == stat.name).unwrap_or(stat);
+
+      rows.push(Row{name: stat.name.clone(), container: stat.container.clone(),
+        cpu: stat.cpuPercentage, cpu_interval: aggregate.cpuPercentage,
+        memory: stat.memoryUsage.bytes(), limit: stat.totalMemory.bytes(),
+        memory_percentage: stat.memoryPercentage, memory_interval: aggregate.memoryUsage.bytes(),
+        net_in: rate(|s| s.netIO.incoming.bytes()), net_out: rate(|s| s.netIO.outgoing.bytes()),
+        block_in: rate(|s| s.blockIO.incoming.bytes()), block_out: rate(|s| s.blockIO.outgoing.bytes()),
+        pids: stat.pids});
+    }
+
+    self.previous = records.into_iter().map(|s| (s.name.clone(), s)).collect();
+    self.disks = disks(&self.args.disks);
+    self.rows = rows;
+    self.updated = Some(now);
+    self.error = None;
+    self.order();
+  }
+
+  fn order(&mut self)
+  {
+    let (column, reverse) = (self.sort, self.reverse);
+    self.rows.sort_by(|a, b|
+        {
+          let ordering = match column
+          {
+            Column::Name => a.name.cmp(&b.name),
+            Column::Cpu => b.cpu.total_cmp(&a.cpu),
+            Column::Memory => b.memory.total_cmp(&a.memory),
+            Column::Net => (b.net_in + b.net_out).total_cmp(&(a.net_in + a.net_out)),
+            Column::Block => (b.block_in + b.block_out).total_cmp(&(a.block_in + a.block_out)),
+            Column::Pids => b.pids.cmp(&a.pids)
+          };
+          if reverse { ordering.reverse() } else { ordering }
+        });
+  }
+
+  /// Handle a key press.  Returns false when the view should be closed.
+  fn key(&mut self, key: KeyEvent) -> bool
+  {
+    match key.code
+    {
+      KeyCode::Char('q') | KeyCode::Esc => return false,
+      KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+      KeyCode::Char('a') => self.sort = Column::Name,
+      KeyCode::Char('c') => self.sort = Column::Cpu,
+      KeyCode::Char('m') => self.sort = Column::Memory,
+      KeyCode::Char('n') => self.sort = Column::Net,
+      KeyCode::Char('b') => self.sort = Column::Block,
+      KeyCode::Char('p') => self.sort = Column::Pids,
+      KeyCode::Char('r') => self.reverse = !self.reverse,
+      _ => {}
+    }
+    self.order();
+    true
+  }
+
+  fn lines(&self) -> Vec<String>
+  {
+    let mode = format!("{:?}", self.args.mode).to_lowercase();
+    let mut lines : Vec<String> = Vec::with_capacity(self.rows.len() + self.disks.len() + 8);
+    lines.push(match self.updated
+      {
+        Some(time) => format!("{} - {} containers, {} over {} minute(s), updated {}", self.args.host, self.rows.len(), mode,
+          self.args.interval, time.format("%H:%M:%S")),
+        None => format!("{} - collecting container statistics...", self.args.host)
+      });
+    if let Some(error) = &self.error { lines.push(error.clone()); }
+    lines.push("sort: a name  c cpu  m memory  n net  b block  p pids  r reverse  q quit".to_string());
+    lines.push(String::new());
+
+    let aggregate = self.args.mode != Mode::Raw;
+    let arrow = if self.reverse { " ^" } else { " v" };
+    let mark = |label: &str, column: Column| if column == self.sort { format!("{}{}", label, arrow) } else { label.to_string() };
+    let cpu_interval = format!("CPU {}", mode.to_uppercase());
+    let memory_interval = format!("MEM {}", mode.to_uppercase());
+
+    let mut headers : Vec<String> = vec![mark("NAME", Column::Name), "CONTAINER".to_string(), mark("CPU %", Column::Cpu)];
+    if aggregate { headers.push(cpu_interval); }
+    headers.extend([mark("MEM USAGE / LIMIT", Column::Memory), "MEM %".to_string()]);
+    if aggregate { headers.push(memory_interval); }
+    headers.extend([mark("NET IN/OUT", Column::Net), mark("BLOCK IN/OUT", Column::Block), mark("PIDS", Column::Pids)]);
+
+    let rows : Vec<Vec<String>> = self.rows.iter().map(|r|
+        {
+          let mut row = vec![r.name.clone(), r.container.clone(), format!("{:.2}%", r.cpu)];
+          if aggregate { row.push(format!("{:.2}%", r.cpu_interval)); }
+          row.extend([format!("{} / {}", human(r.memory), human(r.limit)), format!("{:.2}%", r.memory_percentage)]);
+          if aggregate { row.push(human(r.memory_interval)); }
+          row.extend([
+            format!("{}/s / {}/s", human(r.net_in), human(r.net_out)),
+            format!("{}/s / {}/s", human(r.block_in), human(r.block_out)),
+            r.pids.to_string()
+          ]);
+          row
+        }).collect();
+
+    let headers : Vec<&str> = headers.iter().map(|h| h.as_str()).collect();
+    lines.extend(table(&headers, &rows));
+    if !self.disks.is_empty()
+    {
+      lines.push(String::new());
+      lines.extend(disk_usage(&self.disks));
+    }
+    lines
+  }
+}
+
+fn draw(stdout: &mut Stdout, lines: &[String]) -> io::Result<()>
+{
+  let (width, height) = terminal::size()?;
+  for (i, line) in lines.iter().take(height as usize).enumerate()
+  {
+    let line : String = line.chars().take(width as usize).collect();
+    queue!(stdout, cursor::MoveTo(0, i as u16), Print(line), terminal::Clear(ClearType::UntilNewLine))?;
+  }
+  queue!(stdout, terminal::Clear(ClearType::FromCursorDown))?;
+  stdout.flush()
+}
+
+fn display(state: &mut State, rx: &Receiver<Result<Vec<Stats>>>, term: &Arc<AtomicBool>, stdout: &mut Stdout) -> io::Result<()>
+{
+  let mut dirty = true;
+  while !term.load(Ordering::Relaxed)
+  {
+    loop
+    {
+      match rx.try_recv()
+      {
+        Ok(Ok(records)) => { state.update(records); dirty = true; },
+        Ok(Err(e)) => { state.error = Some(e.to_string()); dirty = true; },
+        Err(TryRecvError::Empty) => break,
+        Err(TryRecvError::Disconnected) => return Err(Error::other("Container statistics collection stopped"))
+      }
+    }
+
+    if dirty
+    {
+      draw(stdout, &state.lines())?;
+      dirty = false;
+    }
+
+    if event::poll(Duration::from_millis(250))?
+    {
+      match event::read()?
+      {
+        Event::Key(key) if key.kind == KeyEventKind::Press =>
+        {
+          if !state.key(key) { break; }
+          dirty = true;
+        },
+        Event::Resize(_, _) => dirty = true,
+        _ => {}
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Collect statistics every sample period on a separate thread and display them until `q` is
+/// pressed.  Collection failures are shown in the view and retried on the next sample.  The
+/// collection thread is stopped, killing a running `docker stats`, and joined before returning.
+pub fn run(args: &Top, term: &Arc<AtomicBool>) -> Result<()>
+{
+  let (tx, rx) = mpsc::channel();
+  let stop = Arc::new(AtomicBool::new(false));
+  let collecting = Arc::clone(&stop);
+  let period = Duration::from_secs(args.sample_period as u64);
+  let collector = thread::spawn(move ||
+      {
+        let mut next = Instant::now();
+        while !collecting.load(Ordering::Relaxed)
+        {
+          if tx.send(statistics(DEFAULT_TIMEOUT, &collecting)).is_err() { break; }
+          next = max(next + period, Instant::now());
+          sleep_until(&collecting, next);
+        }
+      });
+
+  let mut state = State::new(args);
+  let mut stdout = io::stdout();
+  let result = terminal::enable_raw_mode().
+      and_then(|_| execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)).
+      and_then(|_| display(&mut state, &rx, term, &mut stdout));
+  stop.store(true, Ordering::Relaxed);
+  let restored = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen).and_then(|_| terminal::disable_raw_mode());
+  if collector.join().is_err() { return Err(Error::other("Container statistics collection panicked").into()); }
+  result?;
+  Ok(restored?)
+}